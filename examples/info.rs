@@ -20,7 +20,7 @@ fn main() {
     );
     println!(
         "Interrupt event counter: {}",
-        mcp.get_interrupt_event_counter()
+        mcp.interrupt_event_counter(false)
             .expect("Failed to read interrupt count")
     );
     println!(
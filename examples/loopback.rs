@@ -6,6 +6,7 @@ extern crate mcp2210;
 
 use hidapi::HidApi;
 use mcp2210::{open_first, Commands, SpiMode, SpiTransferSettings};
+use std::num::NonZeroU16;
 
 fn main() {
     //! ##################################################################################
@@ -20,13 +21,15 @@ fn main() {
 
     let hidapi_context = HidApi::new().expect("Could not create hidapi context");
     let mut mcp = open_first(&hidapi_context).expect("Failed to connect");
-    mcp.set_spi_transfer_settings(&SpiTransferSettings {
-        bit_rate: 1_000,
-        bytes_per_tx: 2,
+    let settings = SpiTransferSettings {
+        bit_rate: 1_500,
+        bytes_per_tx: NonZeroU16::new(2).unwrap(),
         spi_mode: SpiMode::Mode0,
         ..Default::default()
-    })
-    .expect("Failed to set settings");
+    };
+    settings.validate().expect("Invalid settings");
+    mcp.set_spi_transfer_settings(&settings)
+        .expect("Failed to set settings");
     let mut buf = Vec::new();
     mcp.spi_transfer_to_end(&[0xaa, 0x55], &mut buf)
         .expect("SPI transfer failed");
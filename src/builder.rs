@@ -0,0 +1,92 @@
+// SPDX-FileCopyrightText: 2018-2022 Joonas Javanainen <joonas.javanainen@gmail.com>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use hidapi::HidApi;
+use std::ffi::{CStr, CString};
+use std::thread;
+use std::time::Duration;
+
+use crate::{
+    open_path, open_serial, open_vid_pid, Mcp2210, Mcp2210Error, FACTORY_PID, FACTORY_VID,
+};
+
+/// Builder for opening a MCP2210 device.
+///
+/// The device is selected by path if one is set, otherwise by VID, PID and serial number if a
+/// serial number is set, and otherwise by VID and PID alone.
+#[derive(Clone)]
+pub struct Mcp2210Builder<'a> {
+    hidapi_context: &'a HidApi,
+    vid: u16,
+    pid: u16,
+    serial: Option<String>,
+    path: Option<CString>,
+    read_timeout: Option<Duration>,
+    open_attempts: u32,
+    retry_delay: Duration,
+}
+
+impl<'a> Mcp2210Builder<'a> {
+    pub fn new(hidapi_context: &'a HidApi) -> Mcp2210Builder<'a> {
+        Mcp2210Builder {
+            hidapi_context,
+            vid: FACTORY_VID,
+            pid: FACTORY_PID,
+            serial: None,
+            path: None,
+            read_timeout: None,
+            open_attempts: 1,
+            retry_delay: Duration::ZERO,
+        }
+    }
+    pub fn vid(mut self, vid: u16) -> Self {
+        self.vid = vid;
+        self
+    }
+    pub fn pid(mut self, pid: u16) -> Self {
+        self.pid = pid;
+        self
+    }
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_owned());
+        self
+    }
+    pub fn path(mut self, path: &CStr) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+    /// Tries to open the device up to `attempts` times, sleeping `delay` between attempts.
+    pub fn retry(mut self, attempts: u32, delay: Duration) -> Self {
+        self.open_attempts = attempts.max(1);
+        self.retry_delay = delay;
+        self
+    }
+    pub fn open(&self) -> Result<Mcp2210, Mcp2210Error> {
+        let mut attempt = 1;
+        loop {
+            match self.try_open() {
+                Ok(mut mcp) => {
+                    mcp.set_read_timeout(self.read_timeout);
+                    return Ok(mcp);
+                }
+                Err(err) if attempt >= self.open_attempts => return Err(err),
+                Err(_) => {
+                    attempt += 1;
+                    thread::sleep(self.retry_delay);
+                }
+            }
+        }
+    }
+    fn try_open(&self) -> Result<Mcp2210, Mcp2210Error> {
+        match (&self.path, &self.serial) {
+            (Some(path), _) => open_path(self.hidapi_context, path),
+            (None, Some(serial)) => open_serial(self.hidapi_context, self.vid, self.pid, serial),
+            (None, None) => open_vid_pid(self.hidapi_context, self.vid, self.pid),
+        }
+    }
+}
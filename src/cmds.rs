@@ -2,41 +2,54 @@
 //
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use super::{Buffer, Mcp2210Error};
+use super::{hid_error, Buffer, Mcp2210Error, MAX_SPI_PAYLOAD};
 use crate::types::*;
-use crate::utils::{as_u16, encode_utf16_to_buffer};
+use crate::utils::{as_u16, as_u32, eeprom_crc8, encode_utf16_to_buffer};
 use hidapi::HidResult;
 use std::cmp::min;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sub-command code of the NVRAM SPI transfer settings, used with commands 0x60 and 0x61.
+pub const NVRAM_SPI_SETTINGS_SUBCODE: u8 = 0x10;
+/// Sub-command code of the NVRAM chip settings, used with commands 0x60 and 0x61.
+pub const NVRAM_CHIP_SETTINGS_SUBCODE: u8 = 0x20;
+/// Sub-command code of the NVRAM USB parameters, used with commands 0x60 and 0x61.
+pub const NVRAM_USB_PARAMETERS_SUBCODE: u8 = 0x30;
+/// Sub-command code of the NVRAM USB product name, used with commands 0x60 and 0x61.
+pub const NVRAM_USB_PRODUCT_NAME_SUBCODE: u8 = 0x40;
+/// Sub-command code of the NVRAM USB vendor name, used with commands 0x60 and 0x61.
+pub const NVRAM_USB_VENDOR_NAME_SUBCODE: u8 = 0x50;
 
 pub trait CommandResponse {
     fn command_response(&mut self, cmd: &Buffer, res: &mut Buffer) -> HidResult<()>;
+    /// Sends a complete command report and checks the response code and status.
+    ///
+    /// Every [`Commands`] method goes through this, so it can be overridden to add logging,
+    /// retries or workarounds for device quirks. The default implementation calls
+    /// [`command_response`](CommandResponse::command_response) and then [`check_response`].
+    fn do_command_buf(&mut self, cmd: &Buffer, res: &mut Buffer) -> Result<(), Mcp2210Error> {
+        self.command_response(cmd, res)
+            .map_err(hid_error(command_name(cmd[0], cmd[1])))?;
+        check_response(cmd[0], res)
+    }
+    /// Sends a command and checks the response code and status.
+    ///
+    /// The [`Commands`] methods don't call this, so overriding it has no effect on them.
+    /// Override [`do_command_buf`](CommandResponse::do_command_buf) instead.
+    #[deprecated(note = "not called by `Commands`; override `do_command_buf` instead")]
     fn do_command<F>(&mut self, cmd_code: u8, res: &mut Buffer, f: F) -> Result<(), Mcp2210Error>
     where
+        Self: Sized,
         F: FnOnce(&mut Buffer),
     {
-        let mut cmd: Buffer = [0; 64];
-        cmd[0] = cmd_code;
-        f(&mut cmd);
-        self.command_response(&cmd, res)
-            .map_err(Mcp2210Error::Hid)?;
-        if cmd_code != res[0] {
-            return Err(Mcp2210Error::CommandCode {
-                expected: cmd_code,
-                actual: res[0],
-            });
-        }
-        match res[1] {
-            0x00 => Ok(()),
-            0xf7 => Err(Mcp2210Error::Unavailable),
-            0xf8 => Err(Mcp2210Error::Busy),
-            0xf9 => Err(Mcp2210Error::UnknownCommandCode(cmd_code)),
-            0xfa => Err(Mcp2210Error::EepromWrite),
-            0xfb => Err(Mcp2210Error::AccessDenied),
-            0xfc => Err(Mcp2210Error::AccessRejected),
-            0xfd => Err(Mcp2210Error::AccessDeniedRetry),
-            err_code => Err(Mcp2210Error::UnknownErrorCode(err_code)),
-        }
+        command(self, cmd_code, res, f)
     }
+    /// Sends a sub-command and checks the response code, status and sub-command code.
+    ///
+    /// Like [`do_command`](CommandResponse::do_command), this is not called by the [`Commands`]
+    /// methods.
+    #[deprecated(note = "not called by `Commands`; override `do_command_buf` instead")]
     fn do_sub_command<F>(
         &mut self,
         cmd_code: u8,
@@ -45,75 +58,235 @@ pub trait CommandResponse {
         f: F,
     ) -> Result<(), Mcp2210Error>
     where
+        Self: Sized,
         F: FnOnce(&mut Buffer),
     {
-        self.do_command(cmd_code, res, |cmd| {
-            cmd[1] = sub_cmd_code;
-            f(cmd);
-        })?;
-        if res[2] != sub_cmd_code {
-            Err(Mcp2210Error::SubCommandCode {
-                expected: sub_cmd_code,
-                actual: res[2],
-            })
-        } else {
-            Ok(())
-        }
+        sub_command(self, cmd_code, sub_cmd_code, res, f)
+    }
+}
+
+impl CommandResponse for Box<dyn CommandResponse + '_> {
+    fn command_response(&mut self, cmd: &Buffer, res: &mut Buffer) -> HidResult<()> {
+        (**self).command_response(cmd, res)
+    }
+    fn do_command_buf(&mut self, cmd: &Buffer, res: &mut Buffer) -> Result<(), Mcp2210Error> {
+        (**self).do_command_buf(cmd, res)
+    }
+}
+
+/// Checks the command code and status of a response to the command `cmd_code`.
+///
+/// This is the check done by the default [`CommandResponse::do_command_buf`], and can be used by
+/// implementations that override it.
+pub fn check_response(cmd_code: u8, res: &Buffer) -> Result<(), Mcp2210Error> {
+    if cmd_code != res[0] {
+        return Err(Mcp2210Error::CommandCode {
+            expected: cmd_code,
+            actual: res[0],
+        });
+    }
+    match res[1] {
+        0x00 => Ok(()),
+        0xf7 => Err(Mcp2210Error::Unavailable),
+        0xf8 => Err(Mcp2210Error::Busy),
+        0xf9 => Err(Mcp2210Error::UnknownCommandCode(cmd_code)),
+        0xfa => Err(Mcp2210Error::EepromWrite),
+        0xfb => Err(Mcp2210Error::AccessDenied),
+        0xfc => Err(Mcp2210Error::AccessRejected),
+        0xfd => Err(Mcp2210Error::AccessDeniedRetry),
+        error_code => Err(Mcp2210Error::UnknownErrorCode {
+            cmd_code,
+            error_code,
+        }),
     }
 }
 
 pub trait Commands: CommandResponse {
+    /// Reads the chip status.
+    ///
+    /// If the device answers `Mcp2210Error::Busy`, the query is retried once.
     fn get_chip_status(&mut self) -> Result<ChipStatus, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x10, &mut res, |_| {})?;
-        ChipStatus::from_buffer(&res).map_err(Mcp2210Error::InvalidResponse)
+        match command(self, 0x10, &mut res, |_| {}) {
+            Err(Mcp2210Error::Busy) => command(self, 0x10, &mut res, |_| {})?,
+            result => result?,
+        }
+        ChipStatus::from_buffer(&res)
     }
     fn cancel_spi_transfer(&mut self) -> Result<ChipStatus, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x11, &mut res, |_| {})?;
-        ChipStatus::from_buffer(&res).map_err(Mcp2210Error::InvalidResponse)
+        command(self, 0x11, &mut res, |_| {})?;
+        ChipStatus::from_buffer(&res)
     }
-    fn get_interrupt_event_counter(&mut self) -> Result<u16, Mcp2210Error> {
+    /// Reads the interrupt event counter, optionally resetting it.
+    ///
+    /// Returns the counter value before the reset.
+    fn interrupt_event_counter(&mut self, reset: bool) -> Result<u16, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x12, &mut res, |cmd| {
-            cmd[1] = 0xff;
+        command(self, 0x12, &mut res, |cmd| {
+            cmd[1] = if reset { 0x00 } else { 0xff };
         })?;
         Ok(as_u16(res[4], res[5]))
     }
+    #[deprecated(note = "use `interrupt_event_counter(false)` instead")]
+    fn get_interrupt_event_counter(&mut self) -> Result<u16, Mcp2210Error> {
+        self.interrupt_event_counter(false)
+    }
+    /// Resets the interrupt event counter and returns its value before the reset.
+    #[deprecated(note = "use `interrupt_event_counter(true)` instead")]
     fn reset_interrupt_event_counter(&mut self) -> Result<u16, Mcp2210Error> {
-        let mut res: Buffer = [0; 64];
-        self.do_command(0x12, &mut res, |cmd| {
-            cmd[1] = 0x00;
-        })?;
-        Ok(as_u16(res[4], res[5]))
+        self.interrupt_event_counter(true)
+    }
+    /// Same as [`interrupt_event_counter(true)`](Commands::interrupt_event_counter).
+    fn get_and_reset_interrupt_counter(&mut self) -> Result<u16, Mcp2210Error> {
+        self.interrupt_event_counter(true)
+    }
+    /// Polls the interrupt event counter without resetting it until it reaches `threshold`.
+    ///
+    /// The counter is read every `POLL_INTERRUPT_INTERVAL` (10 ms). Returns the last read counter
+    /// value, or `Mcp2210Error::Timeout` if the threshold was not reached within `timeout`.
+    fn poll_interrupt_counter(
+        &mut self,
+        threshold: u16,
+        timeout: Duration,
+    ) -> Result<u16, Mcp2210Error> {
+        self.poll_interrupt_counter_with_interval(threshold, POLL_INTERRUPT_INTERVAL, timeout)
+    }
+    /// Same as [`poll_interrupt_counter`](Commands::poll_interrupt_counter), but sleeps
+    /// `interval` between reads.
+    fn poll_interrupt_counter_with_interval(
+        &mut self,
+        threshold: u16,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Result<u16, Mcp2210Error> {
+        let start = Instant::now();
+        loop {
+            let count = self.interrupt_event_counter(false)?;
+            if count >= threshold {
+                return Ok(count);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(Mcp2210Error::Timeout);
+            }
+            thread::sleep(interval.min(timeout - elapsed));
+        }
     }
     fn get_chip_settings(&mut self) -> Result<ChipSettings, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x20, &mut res, |_| {})?;
-        ChipSettings::from_buffer(&res).map_err(Mcp2210Error::InvalidResponse)
+        command(self, 0x20, &mut res, |_| {})?;
+        ChipSettings::from_buffer(&res)
     }
+    /// Writes the current chip settings.
+    ///
+    /// With the `log` feature enabled, a warning is logged if the GP6 pin mode and the interrupt
+    /// mode are inconsistent (see [`ChipSettings::interrupt_config_is_consistent`]).
     fn set_chip_settings(&mut self, settings: &ChipSettings) -> Result<(), Mcp2210Error> {
+        #[cfg(feature = "log")]
+        if !settings.interrupt_config_is_consistent() {
+            log::warn!(
+                "GP6 mode {} is inconsistent with interrupt mode {}",
+                settings.gp6_mode,
+                settings.interrupt_mode
+            );
+        }
         let mut res: Buffer = [0; 64];
-        self.do_command(0x21, &mut res, |cmd| {
+        command(self, 0x21, &mut res, |cmd| {
             settings.write_to_buffer(cmd);
         })
     }
+    /// Writes the chip settings only if they differ from the current chip settings.
+    fn apply_chip_settings_diff(&mut self, new: &ChipSettings) -> Result<(), Mcp2210Error> {
+        if self.get_chip_settings()? != *new {
+            self.set_chip_settings(new)?;
+        }
+        Ok(())
+    }
+    /// Switches GP6 to its dedicated interrupt input function and sets the interrupt mode.
+    fn configure_gpio_for_interrupt(&mut self, mode: InterruptMode) -> Result<(), Mcp2210Error> {
+        let settings = self.get_chip_settings()?;
+        self.set_chip_settings(&ChipSettings {
+            gp6_mode: PinMode::Dedicated,
+            interrupt_mode: mode,
+            ..settings
+        })
+    }
+    /// Configures a single-device SPI bus using `cs_pin` as the chip select line.
+    ///
+    /// The pin is switched to chip select mode first, and then the SPI transfer settings are
+    /// updated with the given bit rate and mode and a chip select that is high when idle and low
+    /// when active. The two writes are separate commands, so the configuration is not atomic if
+    /// the second one fails. Returns `Mcp2210Error::InvalidPin` if `cs_pin` is greater than 8.
+    fn configure_spi_with_cs(
+        &mut self,
+        bit_rate: u32,
+        mode: SpiMode,
+        cs_pin: u8,
+    ) -> Result<(), Mcp2210Error> {
+        let mut chip_settings = self.get_chip_settings()?;
+        match chip_settings.pin_mode_mut(cs_pin) {
+            Some(pin_mode) => *pin_mode = PinMode::ChipSelect,
+            None => return Err(Mcp2210Error::InvalidPin(cs_pin)),
+        }
+        self.set_chip_settings(&chip_settings)?;
+        let cs = ChipSelect::from_bits_truncate(1 << cs_pin);
+        let settings = self.get_spi_transfer_settings()?;
+        self.set_spi_transfer_settings(&SpiTransferSettings {
+            bit_rate,
+            spi_mode: mode,
+            cs_idle: ChipSelect::ALL_HIGH,
+            cs_active: ChipSelect::ALL_HIGH - cs,
+            ..settings
+        })
+    }
+    fn factory_reset_chip_settings(&mut self) -> Result<(), Mcp2210Error> {
+        self.set_chip_settings(&ChipSettings::default())
+    }
+    fn factory_reset_spi_settings(&mut self) -> Result<(), Mcp2210Error> {
+        self.set_spi_transfer_settings(&SpiTransferSettings::default())
+    }
     fn set_gpio_value(&mut self, value: GpioValue) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x30, &mut res, |cmd| {
+        command(self, 0x30, &mut res, |cmd| {
             let value = value.bits();
             cmd[4] = value as u8;
             cmd[5] = (value >> 8) as u8;
         })
     }
+    /// Updates only the pins selected by `mask` to the corresponding values in `value`.
+    ///
+    /// This is a read-modify-write operation and is not atomic: pin values changed by someone
+    /// else between the read and the write are overwritten.
+    fn set_gpio_value_mask(
+        &mut self,
+        mask: GpioValue,
+        value: GpioValue,
+    ) -> Result<(), Mcp2210Error> {
+        let current = self.get_gpio_value()?;
+        self.set_gpio_value((current & !mask) | (value & mask))
+    }
     fn get_gpio_value(&mut self) -> Result<GpioValue, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x31, &mut res, |_| {})?;
+        command(self, 0x31, &mut res, |_| {})?;
         Ok(GpioValue::from_bits_truncate(as_u16(res[4], res[5])))
     }
+    /// Reads the value of a single pin (0-8) after checking that it is in GPIO mode.
+    ///
+    /// Returns `Mcp2210Error::InvalidPin` if `pin` is greater than 8, and
+    /// `Mcp2210Error::PinModeConflict` if the pin is not in GPIO mode.
+    fn read_gpio_confirmed(&mut self, pin: u8) -> Result<bool, Mcp2210Error> {
+        if pin > 8 {
+            return Err(Mcp2210Error::InvalidPin(pin));
+        }
+        if self.get_chip_settings()?.pin_mode(pin) != Some(PinMode::Gpio) {
+            return Err(Mcp2210Error::PinModeConflict { pin });
+        }
+        Ok(self.get_gpio_value()?.bits() & (1 << pin) != 0)
+    }
     fn set_gpio_direction(&mut self, direction: GpioDirection) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x32, &mut res, |cmd| {
+        command(self, 0x32, &mut res, |cmd| {
             let direction = direction.bits();
             cmd[4] = direction as u8;
             cmd[5] = (direction >> 8) as u8;
@@ -121,33 +294,103 @@ pub trait Commands: CommandResponse {
     }
     fn get_gpio_direction(&mut self) -> Result<GpioDirection, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x33, &mut res, |_| {})?;
+        command(self, 0x33, &mut res, |_| {})?;
         Ok(GpioDirection::from_bits_truncate(as_u16(res[4], res[5])))
     }
+    /// Sets both the GPIO direction and the GPIO values, ordering the writes to avoid glitches.
+    ///
+    /// Pins that become inputs are switched to inputs before the values are written, and pins
+    /// that become outputs are switched to outputs only after their values have been written. The
+    /// writes are still separate commands, so this is not atomic.
+    fn set_gpio_direction_and_value(
+        &mut self,
+        direction: GpioDirection,
+        value: GpioValue,
+    ) -> Result<(), Mcp2210Error> {
+        let mut current = self.get_gpio_direction()?;
+        let new_inputs = direction - current;
+        if !new_inputs.is_empty() {
+            current |= new_inputs;
+            self.set_gpio_direction(current)?;
+        }
+        self.set_gpio_value(value)?;
+        if current != direction {
+            self.set_gpio_direction(direction)?;
+        }
+        Ok(())
+    }
+    /// Sets the GPIO direction after checking it against the current pin modes.
+    ///
+    /// The hardware ignores the direction of pins that are not in GPIO mode, so this returns
+    /// `Mcp2210Error::PinModeConflict` if a direction bit is set for any such pin.
+    fn set_gpio_direction_checked(&mut self, direction: GpioDirection) -> Result<(), Mcp2210Error> {
+        let settings = self.get_chip_settings()?;
+        for pin in 0..9 {
+            if direction.bits() & (1 << pin) != 0 && settings.pin_mode(pin) != Some(PinMode::Gpio) {
+                return Err(Mcp2210Error::PinModeConflict { pin });
+            }
+        }
+        self.set_gpio_direction(direction)
+    }
     fn set_spi_transfer_settings(
         &mut self,
         settings: &SpiTransferSettings,
     ) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x40, &mut res, |cmd| {
+        command(self, 0x40, &mut res, |cmd| {
             settings.write_to_buffer(cmd);
         })
     }
     fn get_spi_transfer_settings(&mut self) -> Result<SpiTransferSettings, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x41, &mut res, |_| {})?;
-        SpiTransferSettings::from_buffer(&res).map_err(Mcp2210Error::InvalidResponse)
+        command(self, 0x41, &mut res, |_| {})?;
+        SpiTransferSettings::from_buffer(&res)
+    }
+    /// Writes the SPI transfer settings only if they differ from the current settings.
+    ///
+    /// Returns `true` if the settings were written.
+    fn apply_spi_settings_if_changed(
+        &mut self,
+        new: &SpiTransferSettings,
+    ) -> Result<bool, Mcp2210Error> {
+        if self.get_spi_transfer_settings()? == *new {
+            return Ok(false);
+        }
+        self.set_spi_transfer_settings(new)?;
+        Ok(true)
+    }
+    /// Reads the current SPI transfer settings and checks that they match `expected`.
+    fn verify_spi_transfer_settings(
+        &mut self,
+        expected: &SpiTransferSettings,
+    ) -> Result<(), Mcp2210Error> {
+        let actual = self.get_spi_transfer_settings()?;
+        if actual != *expected {
+            return Err(Mcp2210Error::SettingsMismatch {
+                expected: *expected,
+                actual,
+            });
+        }
+        Ok(())
     }
+    /// Sends a single SPI transfer command.
+    ///
+    /// If the device is still busy with the previous transfer command, it rejects the data with
+    /// `Mcp2210Error::Busy`. The response then contains no received data, so nothing is lost and
+    /// the same data can be sent again.
     fn spi_transfer<'a>(
         &mut self,
         data: &[u8],
         res: &'a mut Buffer,
     ) -> Result<SpiTransferResponse<'a>, Mcp2210Error> {
-        if data.len() > 60 {
-            return Err(Mcp2210Error::PayloadSize(data.len()));
+        if data.len() > MAX_SPI_PAYLOAD {
+            return Err(Mcp2210Error::PayloadSize {
+                limit: MAX_SPI_PAYLOAD,
+                actual: data.len(),
+            });
         }
-        let mosi_len = min(data.len(), 60);
-        self.do_command(0x42, res, |cmd| {
+        let mosi_len = min(data.len(), MAX_SPI_PAYLOAD);
+        command(self, 0x42, res, |cmd| {
             cmd[1] = mosi_len as u8;
             cmd[4..][..mosi_len].copy_from_slice(&data[..mosi_len]);
         })?;
@@ -159,9 +402,270 @@ pub trait Commands: CommandResponse {
             })?,
         })
     }
+    /// Sends a single SPI transfer command that makes up a whole SPI transaction.
+    ///
+    /// `settings` are the SPI transfer settings the caller last wrote to the device. If `data` is
+    /// non-empty and its length does not match `settings.bytes_per_tx`, nothing is sent and
+    /// `Mcp2210Error::SettingsMismatch` is returned with the settings the transfer would need.
+    /// Data longer than `MAX_SPI_PAYLOAD` can't be a whole transaction in one command, so it is
+    /// rejected with `Mcp2210Error::PayloadSize` before the settings are compared.
+    ///
+    /// [`spi_transfer`](Commands::spi_transfer) itself stays unchecked: it is also used for the
+    /// individual commands of a longer transaction, whose lengths don't match `bytes_per_tx`, and
+    /// checking it would need an extra settings read for every command.
+    fn spi_transfer_checked<'a>(
+        &mut self,
+        data: &[u8],
+        settings: &SpiTransferSettings,
+        res: &'a mut Buffer,
+    ) -> Result<SpiTransferResponse<'a>, Mcp2210Error> {
+        if data.len() > MAX_SPI_PAYLOAD {
+            return Err(Mcp2210Error::PayloadSize {
+                limit: MAX_SPI_PAYLOAD,
+                actual: data.len(),
+            });
+        }
+        if !data.is_empty() && usize::from(settings.bytes_per_tx.get()) != data.len() {
+            return Err(Mcp2210Error::SettingsMismatch {
+                expected: settings.with_bytes_per_tx_for(data.len())?,
+                actual: *settings,
+            });
+        }
+        self.spi_transfer(data, res)
+    }
+    /// Recovers from an interrupted SPI transfer.
+    ///
+    /// The ongoing transfer is cancelled, and if the device still reports that the bus is busy,
+    /// empty transfers are sent until the device reports the transfer as finished. Returns the
+    /// chip status after recovering, or `Mcp2210Error::Timeout` if the transfer did not finish
+    /// within a bounded number of empty transfers.
+    ///
+    /// The chip status is returned instead of `()` so that the caller can check the bus owner
+    /// before starting a fresh transaction without another
+    /// [`get_chip_status`](Commands::get_chip_status) round trip.
+    fn drain_spi_bus(&mut self) -> Result<ChipStatus, Mcp2210Error> {
+        match self.cancel_spi_transfer() {
+            Ok(status) => return Ok(status),
+            Err(Mcp2210Error::Busy) => (),
+            Err(err) => return Err(err),
+        }
+        let mut res: Buffer = [0; 64];
+        for _ in 0..DRAIN_SPI_ATTEMPTS {
+            match self.spi_transfer(&[], &mut res) {
+                Ok(res) if res.status == SpiTransferStatus::Finished => {
+                    return self.get_chip_status()
+                }
+                Ok(_) | Err(Mcp2210Error::Busy) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Mcp2210Error::Timeout)
+    }
+    /// Runs a few basic diagnostic checks using existing commands.
+    ///
+    /// HID errors are returned as errors, while any other failure is recorded in the result.
+    ///
+    /// # Side effects
+    ///
+    /// The interrupt event counter is reset to zero, so any events counted before the self test
+    /// are lost. The GPIO direction is read and written back unchanged.
+    fn self_test(&mut self) -> Result<SelfTestResult, Mcp2210Error> {
+        let eeprom_readable = soft_failure(self.read_eeprom(0x00))?.is_some();
+        let gpio_configurable = match soft_failure(self.get_gpio_direction())? {
+            Some(direction) => soft_failure(self.set_gpio_direction(direction))?.is_some(),
+            None => false,
+        };
+        let interrupt_counter_resettable = soft_failure(self.interrupt_event_counter(true))?
+            .is_some()
+            && soft_failure(self.interrupt_event_counter(false))? == Some(0);
+        Ok(SelfTestResult {
+            eeprom_readable,
+            gpio_configurable,
+            interrupt_counter_resettable,
+        })
+    }
+    /// Sends all data and appends all received data to `buf`.
+    ///
+    /// Returns the number of received bytes. If `data` is empty, nothing is sent to the device
+    /// and 0 is returned.
+    fn spi_transfer_to_end(
+        &mut self,
+        data: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<usize, Mcp2210Error> {
+        transfer_to_end(self, data, |received| {
+            buf.extend(received);
+            Ok(())
+        })
+    }
+    /// Like [`spi_transfer_to_end`](Commands::spi_transfer_to_end), but returns
+    /// `Mcp2210Error::UnexpectedLength` unless exactly `expected_len` bytes were received.
+    fn spi_transfer_to_end_exact(
+        &mut self,
+        data: &[u8],
+        expected_len: usize,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Mcp2210Error> {
+        let actual = self.spi_transfer_to_end(data, buf)?;
+        if actual != expected_len {
+            return Err(Mcp2210Error::UnexpectedLength {
+                expected: expected_len,
+                actual,
+            });
+        }
+        Ok(())
+    }
+    /// Sends all data and writes all received data to the start of `buf`.
+    ///
+    /// Returns the number of received bytes, or `Mcp2210Error::PayloadSize` if `buf` is too small
+    /// for the received data. In that case the transfer is cancelled before returning, and the
+    /// `PayloadSize` error is returned even if cancelling fails.
+    fn spi_transfer_to_end_into(
+        &mut self,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, Mcp2210Error> {
+        let mut len = 0;
+        let mut overflow = false;
+        let result = transfer_to_end(self, data, |received| {
+            let end = len + received.len();
+            if end > buf.len() {
+                overflow = true;
+                return Err(Mcp2210Error::PayloadSize {
+                    limit: buf.len(),
+                    actual: end,
+                });
+            }
+            buf[len..end].copy_from_slice(received);
+            len = end;
+            Ok(())
+        });
+        if overflow {
+            // The overflow is the error the caller needs, so a failed cancel is only logged
+            let _cancelled = self.cancel_spi_transfer();
+            #[cfg(feature = "log")]
+            if let Err(err) = &_cancelled {
+                log::warn!(
+                    "failed to cancel the SPI transfer after an overflow: {}",
+                    err
+                );
+            }
+        }
+        result
+    }
+    /// Sends `write_data` in one SPI transaction and then reads `read_len` bytes in a second one.
+    ///
+    /// `bytes_per_tx` of the current SPI transfer settings is adjusted for each transaction, so
+    /// chip select is released between them and the configured delays apply. The original
+    /// settings are restored afterwards.
+    ///
+    /// An empty half is skipped, and if both halves are empty no commands are sent at all.
+    fn spi_write_then_read(
+        &mut self,
+        write_data: &[u8],
+        read_len: usize,
+    ) -> Result<Vec<u8>, Mcp2210Error> {
+        if write_data.is_empty() && read_len == 0 {
+            return Ok(Vec::new());
+        }
+        let settings = self.get_spi_transfer_settings()?;
+        let write_settings = if write_data.is_empty() {
+            None
+        } else {
+            Some(settings.with_bytes_per_tx_for(write_data.len())?)
+        };
+        let read_settings = if read_len == 0 {
+            None
+        } else {
+            Some(settings.with_bytes_per_tx_for(read_len)?)
+        };
+        let mut result = Ok(());
+        if let Some(write_settings) = write_settings {
+            result = self.set_spi_transfer_settings(&write_settings);
+            if result.is_ok() {
+                result = self
+                    .spi_transfer_to_end(write_data, &mut Vec::new())
+                    .map(|_| ());
+            }
+        }
+        let mut buf = Vec::with_capacity(read_len);
+        if let Some(read_settings) = read_settings {
+            if result.is_ok() {
+                result = self.set_spi_transfer_settings(&read_settings);
+            }
+            if result.is_ok() {
+                result = self
+                    .spi_transfer_to_end(&vec![0; read_len], &mut buf)
+                    .map(|_| ());
+            }
+        }
+        let restored = self.set_spi_transfer_settings(&settings);
+        result.and(restored)?;
+        Ok(buf)
+    }
+    /// Sends a 16-bit word as a single SPI transaction.
+    ///
+    /// The SPI transfer settings are read first, and `bytes_per_tx` is set to 2 if it has a
+    /// different value. The new value is left in place after the transfer.
+    fn spi_write_u16(&mut self, value: u16, endian: Endian) -> Result<(), Mcp2210Error> {
+        let data = match endian {
+            Endian::Big => value.to_be_bytes(),
+            Endian::Little => value.to_le_bytes(),
+        };
+        ensure_bytes_per_tx(self, 2)?;
+        spi_transfer_word(self, data).map(|_| ())
+    }
+    /// Reads a 16-bit word as a single SPI transaction, sending zeros.
+    ///
+    /// Like [`spi_write_u16`](Commands::spi_write_u16), this sets `bytes_per_tx` to 2 if needed.
+    fn spi_read_u16(&mut self, endian: Endian) -> Result<u16, Mcp2210Error> {
+        ensure_bytes_per_tx(self, 2)?;
+        let buf = spi_transfer_word(self, [0; 2])?;
+        Ok(match endian {
+            Endian::Big => u16::from_be_bytes(buf),
+            Endian::Little => u16::from_le_bytes(buf),
+        })
+    }
+    /// Sends a 32-bit word as a single SPI transaction.
+    ///
+    /// The SPI transfer settings are read first, and `bytes_per_tx` is set to 4 if it has a
+    /// different value. The new value is left in place after the transfer.
+    fn spi_write_u32(&mut self, value: u32, endian: Endian) -> Result<(), Mcp2210Error> {
+        let data = match endian {
+            Endian::Big => value.to_be_bytes(),
+            Endian::Little => value.to_le_bytes(),
+        };
+        ensure_bytes_per_tx(self, 4)?;
+        spi_transfer_word(self, data).map(|_| ())
+    }
+    /// Reads a 32-bit word as a single SPI transaction, sending zeros.
+    ///
+    /// Like [`spi_write_u32`](Commands::spi_write_u32), this sets `bytes_per_tx` to 4 if needed.
+    fn spi_read_u32(&mut self, endian: Endian) -> Result<u32, Mcp2210Error> {
+        ensure_bytes_per_tx(self, 4)?;
+        let buf = spi_transfer_word(self, [0; 4])?;
+        Ok(match endian {
+            Endian::Big => u32::from_be_bytes(buf),
+            Endian::Little => u32::from_le_bytes(buf),
+        })
+    }
+    /// Sends the data and checks that exactly the same data was received.
+    ///
+    /// Intended for loopback tests where MOSI is wired directly to MISO.
+    fn spi_assert_loopback(&mut self, data: &[u8]) -> Result<(), Mcp2210Error> {
+        let mut received = Vec::with_capacity(data.len());
+        self.spi_transfer_to_end(data, &mut received)?;
+        if received != data {
+            return Err(Mcp2210Error::LoopbackMismatch {
+                sent: data.to_vec(),
+                received,
+            });
+        }
+        Ok(())
+    }
     fn read_eeprom(&mut self, addr: u8) -> Result<u8, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x50, &mut res, |cmd| {
+        command(self, 0x50, &mut res, |cmd| {
             cmd[1] = addr;
         })?;
         if res[2] != addr {
@@ -172,60 +676,223 @@ pub trait Commands: CommandResponse {
         }
         Ok(res[3])
     }
+    /// Reads a single EEPROM byte into a caller-supplied response buffer.
+    ///
+    /// Unlike [`read_eeprom`](Commands::read_eeprom), this does not check the address echoed
+    /// back by the device. The command code and status are still checked. Every byte is still a
+    /// separate HID round-trip, because the device has no command for reading several bytes.
+    fn read_eeprom_raw(&mut self, addr: u8, res: &mut Buffer) -> Result<u8, Mcp2210Error> {
+        command(self, 0x50, res, |cmd| {
+            cmd[1] = addr;
+        })?;
+        Ok(res[3])
+    }
+    /// Reads the whole 256-byte EEPROM.
+    fn read_eeprom_all(&mut self) -> Result<Vec<u8>, Mcp2210Error> {
+        let mut res: Buffer = [0; 64];
+        (0..=255)
+            .map(|addr| self.read_eeprom_raw(addr, &mut res))
+            .collect()
+    }
     fn write_eeprom(&mut self, addr: u8, data: u8) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x51, &mut res, |cmd| {
+        command(self, 0x51, &mut res, |cmd| {
             cmd[1] = addr;
             cmd[2] = data;
         })
     }
+    /// Reads the current EEPROM byte and then writes `data` over it.
+    ///
+    /// Returns the previous byte, so the caller can check whether it was in the erased state
+    /// (0xff). With the `log` feature enabled, a warning is also logged if it was not.
+    fn write_eeprom_checked(&mut self, addr: u8, data: u8) -> Result<u8, Mcp2210Error> {
+        let previous = self.read_eeprom(addr)?;
+        #[cfg(feature = "log")]
+        if previous != 0xff {
+            log::warn!(
+                "overwriting EEPROM byte {:02x} at {:02x} with {:02x}",
+                previous,
+                addr,
+                data
+            );
+        }
+        self.write_eeprom(addr, data)?;
+        Ok(previous)
+    }
+    fn read_eeprom_range(&mut self, start: u8, len: usize) -> Result<Vec<u8>, Mcp2210Error> {
+        check_eeprom_range(start, len)?;
+        (0..len)
+            .map(|offset| self.read_eeprom(start + offset as u8))
+            .collect()
+    }
+    fn write_eeprom_slice(&mut self, start: u8, data: &[u8]) -> Result<(), Mcp2210Error> {
+        self.write_eeprom_range_with_progress(start, data, &mut |_, _| {})
+    }
+    /// Writes the data to the EEPROM, calling `progress` with `(bytes_written, total_bytes)`
+    /// after every byte.
+    ///
+    /// A byte that fails with `Mcp2210Error::EepromWrite` is retried up to two more times after a
+    /// short delay. If every attempt fails, `Mcp2210Error::EepromWriteAt` reports the address.
+    fn write_eeprom_range_with_progress(
+        &mut self,
+        start: u8,
+        data: &[u8],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> Result<(), Mcp2210Error> {
+        check_eeprom_range(start, data.len())?;
+        for (offset, &byte) in data.iter().enumerate() {
+            let addr = start + offset as u8;
+            let mut attempt = 1;
+            loop {
+                match self.write_eeprom(addr, byte) {
+                    Err(Mcp2210Error::EepromWrite) if attempt < EEPROM_WRITE_ATTEMPTS => {
+                        #[cfg(feature = "log")]
+                        log::warn!(
+                            "EEPROM write at {:02x} failed, retrying (attempt {} of {})",
+                            addr,
+                            attempt + 1,
+                            EEPROM_WRITE_ATTEMPTS
+                        );
+                        thread::sleep(EEPROM_RETRY_DELAY);
+                        attempt += 1;
+                    }
+                    Err(Mcp2210Error::EepromWrite) => {
+                        return Err(Mcp2210Error::EepromWriteAt { addr })
+                    }
+                    result => break result?,
+                }
+            }
+            progress(offset + 1, data.len());
+        }
+        Ok(())
+    }
+    /// Writes the data followed by its CRC-8 checksum to the EEPROM.
+    fn write_eeprom_with_crc(&mut self, start: u8, data: &[u8]) -> Result<(), Mcp2210Error> {
+        check_eeprom_range(start, data.len() + 1)?;
+        self.write_eeprom_slice(start, data)?;
+        self.write_eeprom(start + data.len() as u8, eeprom_crc8(data))
+    }
+    /// Reads `len` bytes of data followed by a CRC-8 checksum from the EEPROM and verifies the
+    /// checksum.
+    fn read_eeprom_with_crc(&mut self, start: u8, len: u8) -> Result<Vec<u8>, Mcp2210Error> {
+        let mut data = self.read_eeprom_range(start, usize::from(len) + 1)?;
+        let actual = data.pop().unwrap_or_default();
+        let expected = eeprom_crc8(&data);
+        if actual != expected {
+            return Err(Mcp2210Error::EepromCrcMismatch { expected, actual });
+        }
+        Ok(data)
+    }
+    /// Reads a little-endian `u16` from two consecutive EEPROM cells.
+    fn read_eeprom_u16(&mut self, addr: u8) -> Result<u16, Mcp2210Error> {
+        let data = self.read_eeprom_range(addr, 2)?;
+        Ok(as_u16(data[0], data[1]))
+    }
+    fn write_eeprom_u16(&mut self, addr: u8, value: u16) -> Result<(), Mcp2210Error> {
+        self.write_eeprom_slice(addr, &value.to_le_bytes())
+    }
+    /// Reads a little-endian `u32` from four consecutive EEPROM cells.
+    fn read_eeprom_u32(&mut self, addr: u8) -> Result<u32, Mcp2210Error> {
+        let data = self.read_eeprom_range(addr, 4)?;
+        Ok(as_u32(data[0], data[1], data[2], data[3]))
+    }
+    fn write_eeprom_u32(&mut self, addr: u8, value: u32) -> Result<(), Mcp2210Error> {
+        self.write_eeprom_slice(addr, &value.to_le_bytes())
+    }
+    /// Reads a zero-padded UTF-8 string from a `max_len` byte EEPROM field.
+    fn read_eeprom_string(&mut self, start: u8, max_len: u8) -> Result<String, Mcp2210Error> {
+        let mut data = self.read_eeprom_range(start, usize::from(max_len))?;
+        let len = data.iter().rposition(|&b| b != 0).map_or(0, |pos| pos + 1);
+        data.truncate(len);
+        String::from_utf8(data).map_err(|err| Mcp2210Error::EepromData(err.to_string()))
+    }
+    /// Writes a string to a `field_len` byte EEPROM field, padding it with zero bytes.
+    fn write_eeprom_string(
+        &mut self,
+        start: u8,
+        s: &str,
+        field_len: u8,
+    ) -> Result<(), Mcp2210Error> {
+        if s.len() > usize::from(field_len) {
+            return Err(Mcp2210Error::EepromData(format!(
+                "String is {} bytes long, but the field is only {} bytes",
+                s.len(),
+                field_len
+            )));
+        }
+        let mut data = s.as_bytes().to_vec();
+        data.resize(usize::from(field_len), 0);
+        self.write_eeprom_slice(start, &data)
+    }
     fn set_nvram_spi_transfer_settings(
         &mut self,
         settings: &SpiTransferSettings,
     ) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x60, 0x10, &mut res, |cmd| {
+        sub_command(self, 0x60, NVRAM_SPI_SETTINGS_SUBCODE, &mut res, |cmd| {
             settings.write_to_buffer(cmd);
         })
     }
+    /// Writes the power-up chip settings to the NVRAM, taking the password as raw bytes.
+    #[deprecated(note = "use `set_nvram_chip_settings_with_password` instead")]
     fn set_nvram_chip_settings(
         &mut self,
         settings: &ChipSettings,
         password: Option<&[u8; 8]>,
     ) -> Result<(), Mcp2210Error> {
-        let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x60, 0x20, &mut res, |cmd| {
-            settings.write_to_buffer(cmd);
-            if let Some(password) = password {
-                cmd[19..27].copy_from_slice(password);
-            }
-        })
+        let password = password.map(|bytes| Password::new(*bytes));
+        self.set_nvram_chip_settings_with_password(settings, password.as_ref())
+    }
+    /// Writes the power-up chip settings to the NVRAM.
+    ///
+    /// Returns `Mcp2210Error::WouldPermLock` if the settings would permanently lock the NVRAM.
+    /// Use [`set_nvram_chip_settings_locked`](Commands::set_nvram_chip_settings_locked) for that.
+    fn set_nvram_chip_settings_with_password(
+        &mut self,
+        settings: &ChipSettings,
+        password: Option<&Password>,
+    ) -> Result<(), Mcp2210Error> {
+        if settings.nvram_access_control.is_locked() {
+            return Err(Mcp2210Error::WouldPermLock);
+        }
+        write_nvram_chip_settings(self, settings, password)
+    }
+    /// Writes the power-up chip settings to the NVRAM, allowing it to be permanently locked.
+    ///
+    /// Once locked, the NVRAM can never be changed again.
+    fn set_nvram_chip_settings_locked(
+        &mut self,
+        settings: &ChipSettings,
+        password: Option<&Password>,
+        _confirm: CommitPermanentLock,
+    ) -> Result<(), Mcp2210Error> {
+        write_nvram_chip_settings(self, settings, password)
     }
     fn set_nvram_usb_parameters(&mut self, params: &UsbParameters) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x60, 0x30, &mut res, |cmd| {
+        sub_command(self, 0x60, NVRAM_USB_PARAMETERS_SUBCODE, &mut res, |cmd| {
             params.write_to_buffer(cmd);
         })
     }
     fn set_nvram_usb_product_name(&mut self, name: &str) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        let size = name.encode_utf16().count();
-        if size > 29 {
-            return Err(Mcp2210Error::StringSize(size));
-        }
-        self.do_sub_command(0x60, 0x40, &mut res, |cmd| {
-            cmd[4] = (size as u8) * 2 + 2;
-            cmd[5] = 0x03;
-            encode_utf16_to_buffer(name, &mut cmd[6..]);
-        })
+        let size = check_usb_string(name)?;
+        sub_command(
+            self,
+            0x60,
+            NVRAM_USB_PRODUCT_NAME_SUBCODE,
+            &mut res,
+            |cmd| {
+                cmd[4] = (size as u8) * 2 + 2;
+                cmd[5] = 0x03;
+                encode_utf16_to_buffer(name, &mut cmd[6..]);
+            },
+        )
     }
     fn set_nvram_usb_vendor_name(&mut self, name: &str) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        let size = name.encode_utf16().count();
-        if size > 29 {
-            return Err(Mcp2210Error::StringSize(size));
-        }
-        self.do_sub_command(0x60, 0x50, &mut res, |cmd| {
+        let size = check_usb_string(name)?;
+        sub_command(self, 0x60, NVRAM_USB_VENDOR_NAME_SUBCODE, &mut res, |cmd| {
             cmd[4] = (size as u8) * 2 + 2;
             cmd[5] = 0x03;
             encode_utf16_to_buffer(name, &mut cmd[6..]);
@@ -233,101 +900,667 @@ pub trait Commands: CommandResponse {
     }
     fn get_nvram_spi_transfer_settings(&mut self) -> Result<SpiTransferSettings, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x61, 0x10, &mut res, |_| {})?;
-        SpiTransferSettings::from_buffer(&res).map_err(Mcp2210Error::InvalidResponse)
+        sub_command(self, 0x61, NVRAM_SPI_SETTINGS_SUBCODE, &mut res, |_| {})?;
+        SpiTransferSettings::from_buffer(&res)
     }
     fn get_nvram_chip_settings(&mut self) -> Result<ChipSettings, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x61, 0x20, &mut res, |_| {})?;
-        ChipSettings::from_buffer(&res).map_err(Mcp2210Error::InvalidResponse)
+        sub_command(self, 0x61, NVRAM_CHIP_SETTINGS_SUBCODE, &mut res, |_| {})?;
+        ChipSettings::from_buffer(&res)
     }
     fn get_nvram_usb_parameters(&mut self) -> Result<UsbParameters, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x61, 0x30, &mut res, |_| {})?;
-        UsbParameters::from_buffer(&res).map_err(Mcp2210Error::InvalidResponse)
+        sub_command(self, 0x61, NVRAM_USB_PARAMETERS_SUBCODE, &mut res, |_| {})?;
+        UsbParameters::from_buffer(&res)
+    }
+    /// Checks whether the NVRAM chip settings match the factory defaults.
+    fn nvram_chip_settings_are_default(&mut self) -> Result<bool, Mcp2210Error> {
+        Ok(self.get_nvram_chip_settings()? == ChipSettings::default())
+    }
+    /// Checks whether the NVRAM SPI transfer settings match the factory defaults.
+    fn nvram_spi_transfer_settings_are_default(&mut self) -> Result<bool, Mcp2210Error> {
+        Ok(self.get_nvram_spi_transfer_settings()? == SpiTransferSettings::default())
     }
+    /// Checks whether the NVRAM USB parameters match the factory defaults.
+    fn nvram_usb_parameters_are_factory(&mut self) -> Result<bool, Mcp2210Error> {
+        Ok(self.get_nvram_usb_parameters()? == UsbParameters::default())
+    }
+    /// Reads the USB product name, replacing invalid UTF-16 data with U+FFFD.
     fn get_nvram_usb_product_name(&mut self) -> Result<String, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x61, 0x40, &mut res, |_| {})?;
-        let str_bytes = (res[4] - 2) as usize;
-        let str_chars = str_bytes / 2;
-        let mut char_buf = [0; 29];
-        for (idx, chunk) in res[6..][..str_bytes].chunks(2).enumerate() {
-            char_buf[idx] = as_u16(chunk[0], chunk[1]);
-        }
-        Ok(String::from_utf16_lossy(&char_buf[..str_chars]))
+        sub_command(self, 0x61, NVRAM_USB_PRODUCT_NAME_SUBCODE, &mut res, |_| {})?;
+        Ok(String::from_utf16_lossy(&decode_usb_string(&res)?))
+    }
+    /// Reads the USB product name, failing if it is not valid UTF-16.
+    fn get_nvram_usb_product_name_strict(&mut self) -> Result<String, Mcp2210Error> {
+        let mut res: Buffer = [0; 64];
+        sub_command(self, 0x61, NVRAM_USB_PRODUCT_NAME_SUBCODE, &mut res, |_| {})?;
+        String::from_utf16(&decode_usb_string(&res)?).map_err(|err| {
+            Mcp2210Error::InvalidResponse(format!("Invalid USB product name: {}", err))
+        })
     }
+    /// Reads the USB vendor name, replacing invalid UTF-16 data with U+FFFD.
     fn get_nvram_usb_vendor_name(&mut self) -> Result<String, Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_sub_command(0x61, 0x50, &mut res, |_| {})?;
-        let str_bytes = (res[4] - 2) as usize;
-        let str_chars = str_bytes / 2;
-        let mut char_buf = [0; 29];
-        for (idx, chunk) in res[6..][..str_bytes].chunks(2).enumerate() {
-            char_buf[idx] = as_u16(chunk[0], chunk[1]);
+        sub_command(self, 0x61, NVRAM_USB_VENDOR_NAME_SUBCODE, &mut res, |_| {})?;
+        Ok(String::from_utf16_lossy(&decode_usb_string(&res)?))
+    }
+    /// Reads the USB vendor name, failing if it is not valid UTF-16.
+    fn get_nvram_usb_vendor_name_strict(&mut self) -> Result<String, Mcp2210Error> {
+        let mut res: Buffer = [0; 64];
+        sub_command(self, 0x61, NVRAM_USB_VENDOR_NAME_SUBCODE, &mut res, |_| {})?;
+        String::from_utf16(&decode_usb_string(&res)?).map_err(|err| {
+            Mcp2210Error::InvalidResponse(format!("Invalid USB vendor name: {}", err))
+        })
+    }
+    /// Reads the USB product and vendor names, returned as `(product_name, vendor_name)`.
+    ///
+    /// Invalid UTF-16 data is replaced with U+FFFD.
+    fn get_usb_descriptor_strings(&mut self) -> Result<(String, String), Mcp2210Error> {
+        let product_name = self.get_nvram_usb_product_name()?;
+        let vendor_name = self.get_nvram_usb_vendor_name()?;
+        Ok((product_name, vendor_name))
+    }
+    fn get_nvram_snapshot(&mut self) -> Result<NvramSnapshot, Mcp2210Error> {
+        Ok(NvramSnapshot {
+            spi_transfer_settings: self.get_nvram_spi_transfer_settings()?,
+            chip_settings: self.get_nvram_chip_settings()?,
+            usb_parameters: self.get_nvram_usb_parameters()?,
+            usb_product_name: self.get_nvram_usb_product_name()?,
+            usb_vendor_name: self.get_nvram_usb_vendor_name()?,
+        })
+    }
+    /// Writes only the NVRAM settings that differ from the current NVRAM contents.
+    ///
+    /// Returns true if at least one setting had to be written.
+    fn apply_nvram_if_changed(&mut self, snapshot: &NvramSnapshot) -> Result<bool, Mcp2210Error> {
+        let current = self.get_nvram_snapshot()?;
+        let mut changed = false;
+        if current.spi_transfer_settings != snapshot.spi_transfer_settings {
+            self.set_nvram_spi_transfer_settings(&snapshot.spi_transfer_settings)?;
+            changed = true;
+        }
+        if current.chip_settings != snapshot.chip_settings {
+            self.set_nvram_chip_settings_with_password(&snapshot.chip_settings, None)?;
+            changed = true;
+        }
+        if current.usb_parameters != snapshot.usb_parameters {
+            self.set_nvram_usb_parameters(&snapshot.usb_parameters)?;
+            changed = true;
+        }
+        if current.usb_product_name != snapshot.usb_product_name {
+            self.set_nvram_usb_product_name(&snapshot.usb_product_name)?;
+            changed = true;
+        }
+        if current.usb_vendor_name != snapshot.usb_vendor_name {
+            self.set_nvram_usb_vendor_name(&snapshot.usb_vendor_name)?;
+            changed = true;
         }
-        Ok(String::from_utf16_lossy(&char_buf[..str_chars]))
+        Ok(changed)
+    }
+    /// Writes every setting of the snapshot to the NVRAM.
+    ///
+    /// The five NVRAM writes are separate commands. If one of them fails,
+    /// `Mcp2210Error::PartialNvramWrite` reports how many writes completed before the failure, so
+    /// the NVRAM may be left with a mix of old and new settings.
+    fn write_nvram_all(
+        &mut self,
+        snapshot: &NvramSnapshot,
+        password: Option<&Password>,
+    ) -> Result<(), Mcp2210Error> {
+        let mut completed = 0;
+        let mut step = |result: Result<(), Mcp2210Error>| match result {
+            Ok(()) => {
+                completed += 1;
+                Ok(())
+            }
+            Err(err) => Err(Mcp2210Error::PartialNvramWrite {
+                completed,
+                error: Box::new(err),
+            }),
+        };
+        step(self.set_nvram_spi_transfer_settings(&snapshot.spi_transfer_settings))?;
+        step(self.set_nvram_chip_settings_with_password(&snapshot.chip_settings, password))?;
+        step(self.set_nvram_usb_parameters(&snapshot.usb_parameters))?;
+        step(self.set_nvram_usb_product_name(&snapshot.usb_product_name))?;
+        step(self.set_nvram_usb_vendor_name(&snapshot.usb_vendor_name))
+    }
+    /// Restores the factory default NVRAM contents, writing only the settings that differ.
+    fn factory_reset_nvram(&mut self) -> Result<(), Mcp2210Error> {
+        self.apply_nvram_if_changed(&NvramSnapshot::default())?;
+        Ok(())
     }
     fn send_access_password(&mut self, password: &[u8; 8]) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x70, &mut res, |cmd| {
+        command(self, 0x70, &mut res, |cmd| {
             cmd[4..11].copy_from_slice(password);
         })
     }
+    /// Responds to an SPI bus release request from an external master.
+    ///
+    /// `ack_value` is only meaningful while the external master is releasing the bus: `true`
+    /// accepts the release and `false` rejects it.
     fn request_bus_release(&mut self, ack_value: bool) -> Result<(), Mcp2210Error> {
         let mut res: Buffer = [0; 64];
-        self.do_command(0x80, &mut res, |cmd| {
+        command(self, 0x80, &mut res, |cmd| {
             cmd[1] = if ack_value { 0x01 } else { 0x00 };
         })
     }
+    /// Sends an arbitrary command and returns the raw response.
+    ///
+    /// The payload is copied to the command report right after the command code, filling the rest
+    /// of the report (bytes 1 to 63). This is wider than a 60-byte payload because several commands
+    /// use the whole report, e.g. an SPI transfer carries up to 60 data bytes at bytes 4 to 63,
+    /// and the NVRAM USB name commands put the string descriptor at bytes 4 to 63.
+    ///
+    /// Like every other command, it is sent through
+    /// [`do_command_buf`](CommandResponse::do_command_buf). Apart from the command code and status
+    /// checks done there, this bypasses all validation, and is intended for advanced use or
+    /// testing future firmware features.
+    fn raw_command(&mut self, cmd_code: u8, payload: &[u8; 63]) -> Result<Buffer, Mcp2210Error> {
+        let mut res: Buffer = [0; 64];
+        command(self, cmd_code, &mut res, |cmd| {
+            cmd[1..].copy_from_slice(payload);
+        })?;
+        Ok(res)
+    }
+    /// Accepts a pending SPI bus release request.
+    ///
+    /// Returns `Mcp2210Error::NoBusReleasePending` if no release request is pending.
+    fn accept_bus_release(&mut self) -> Result<(), Mcp2210Error> {
+        if !self.get_chip_status()?.is_bus_release_pending {
+            return Err(Mcp2210Error::NoBusReleasePending);
+        }
+        self.request_bus_release(true)
+    }
+    /// Rejects a pending SPI bus release request.
+    ///
+    /// Returns `Mcp2210Error::NoBusReleasePending` if no release request is pending.
+    fn reject_bus_release(&mut self) -> Result<(), Mcp2210Error> {
+        if !self.get_chip_status()?.is_bus_release_pending {
+            return Err(Mcp2210Error::NoBusReleasePending);
+        }
+        self.request_bus_release(false)
+    }
 }
 
 impl<T> Commands for T where T: CommandResponse {}
 
-#[cfg(test)]
-struct TestTx {
-    cmd: Buffer,
-    res: Buffer,
+/// Commands that take generic parameters, and are therefore not available through
+/// `dyn Commands`.
+pub trait CommandsExt: Commands {
+    /// Starts an SPI transfer that yields the response of every transfer command separately.
+    ///
+    /// Data is sent in chunks of at most `MAX_SPI_PAYLOAD` bytes, and once all data has been sent,
+    /// empty transfer commands are sent until the device reports the transfer as finished.
+    /// Commands rejected with `Mcp2210Error::Busy` are retried.
+    fn spi_transfer_chunked<'a, 'b>(
+        &'a mut self,
+        data: &'b [u8],
+    ) -> SpiTransferChunks<'a, 'b, Self> {
+        SpiTransferChunks {
+            mcp: self,
+            data,
+            frame_index: 0,
+            done: false,
+        }
+    }
+    /// Reads a value written by [`write_eeprom_structured`](CommandsExt::write_eeprom_structured).
+    #[cfg(feature = "serde")]
+    fn read_eeprom_structured<T: serde::de::DeserializeOwned>(
+        &mut self,
+        start: u8,
+    ) -> Result<T, Mcp2210Error> {
+        let len = usize::from(self.read_eeprom(start)?);
+        check_eeprom_range(start, 1 + len)?;
+        let data = if len == 0 {
+            Vec::new()
+        } else {
+            self.read_eeprom_range(start + 1, len)?
+        };
+        bincode::deserialize(&data).map_err(|err| Mcp2210Error::EepromData(err.to_string()))
+    }
+    /// Serializes a value with bincode and writes it to the EEPROM, preceded by a length byte.
+    #[cfg(feature = "serde")]
+    fn write_eeprom_structured<T: serde::Serialize>(
+        &mut self,
+        start: u8,
+        value: &T,
+    ) -> Result<(), Mcp2210Error> {
+        let data =
+            bincode::serialize(value).map_err(|err| Mcp2210Error::EepromData(err.to_string()))?;
+        let len = u8::try_from(data.len()).map_err(|_| Mcp2210Error::EepromRange {
+            start,
+            len: data.len() + 1,
+        })?;
+        check_eeprom_range(start, data.len() + 1)?;
+        self.write_eeprom(start, len)?;
+        self.write_eeprom_slice(start + 1, &data)
+    }
 }
 
-#[cfg(test)]
-impl TestTx {
-    fn new(res: &[u8]) -> TestTx {
-        assert!(res.len() <= 64);
-        let mut tx = TestTx {
-            cmd: [0; 64],
-            res: [0; 64],
-        };
-        tx.res[..res.len()].copy_from_slice(res);
-        tx
+impl<T> CommandsExt for T where T: Commands + ?Sized {}
+
+fn command<T, F>(mcp: &mut T, cmd_code: u8, res: &mut Buffer, f: F) -> Result<(), Mcp2210Error>
+where
+    T: CommandResponse + ?Sized,
+    F: FnOnce(&mut Buffer),
+{
+    #[cfg(feature = "tracing")]
+    let span = tracing::trace_span!(
+        "do_command",
+        cmd_code,
+        sub_cmd_code = tracing::field::Empty,
+        status_code = tracing::field::Empty
+    );
+    #[cfg(feature = "tracing")]
+    let _enter = span.enter();
+    let mut cmd: Buffer = [0; 64];
+    cmd[0] = cmd_code;
+    f(&mut cmd);
+    #[cfg(feature = "tracing")]
+    if has_sub_command(cmd_code) {
+        span.record("sub_cmd_code", cmd[1]);
+    }
+    #[cfg(feature = "log")]
+    if has_sub_command(cmd_code) {
+        log::trace!("command {:02x} (sub-command {:02x})", cmd_code, cmd[1]);
+    } else {
+        log::trace!("command {:02x}", cmd_code);
     }
+    let result = mcp.do_command_buf(&cmd, res);
+    if !matches!(result, Err(Mcp2210Error::Hid { .. })) {
+        #[cfg(feature = "log")]
+        log::trace!("response {:02x}, status {:02x}", res[0], res[1]);
+        #[cfg(feature = "tracing")]
+        span.record("status_code", res[1]);
+    }
+    result
 }
 
-#[cfg(test)]
-impl CommandResponse for TestTx {
-    fn command_response(&mut self, cmd: &Buffer, res: &mut Buffer) -> HidResult<()> {
-        self.cmd.copy_from_slice(cmd);
-        res.copy_from_slice(&self.res);
-        Ok(())
+fn command_name(cmd_code: u8, sub_cmd_code: u8) -> &'static str {
+    match (cmd_code, sub_cmd_code) {
+        (0x10, _) => "get_chip_status",
+        (0x11, _) => "cancel_spi_transfer",
+        (0x12, _) => "interrupt_event_counter",
+        (0x20, _) => "get_chip_settings",
+        (0x21, _) => "set_chip_settings",
+        (0x30, _) => "set_gpio_value",
+        (0x31, _) => "get_gpio_value",
+        (0x32, _) => "set_gpio_direction",
+        (0x33, _) => "get_gpio_direction",
+        (0x40, _) => "set_spi_transfer_settings",
+        (0x41, _) => "get_spi_transfer_settings",
+        (0x42, _) => "spi_transfer",
+        (0x50, _) => "read_eeprom",
+        (0x51, _) => "write_eeprom",
+        (0x60, NVRAM_SPI_SETTINGS_SUBCODE) => "set_nvram_spi_transfer_settings",
+        (0x60, NVRAM_CHIP_SETTINGS_SUBCODE) => "set_nvram_chip_settings",
+        (0x60, NVRAM_USB_PARAMETERS_SUBCODE) => "set_nvram_usb_parameters",
+        (0x60, NVRAM_USB_PRODUCT_NAME_SUBCODE) => "set_nvram_usb_product_name",
+        (0x60, NVRAM_USB_VENDOR_NAME_SUBCODE) => "set_nvram_usb_vendor_name",
+        (0x61, NVRAM_SPI_SETTINGS_SUBCODE) => "get_nvram_spi_transfer_settings",
+        (0x61, NVRAM_CHIP_SETTINGS_SUBCODE) => "get_nvram_chip_settings",
+        (0x61, NVRAM_USB_PARAMETERS_SUBCODE) => "get_nvram_usb_parameters",
+        (0x61, NVRAM_USB_PRODUCT_NAME_SUBCODE) => "get_nvram_usb_product_name",
+        (0x61, NVRAM_USB_VENDOR_NAME_SUBCODE) => "get_nvram_usb_vendor_name",
+        (0x70, _) => "send_access_password",
+        (0x80, _) => "request_bus_release",
+        _ => "raw_command",
     }
 }
 
-#[test]
-fn test_get_chip_status() {
-    let mut tx = TestTx::new(&[0x10, 0x00, 0x01, 0x02, 42, 0x01]);
-    let status = tx.get_chip_status().unwrap();
-    let mut expected_cmd = [0; 64];
-    expected_cmd[0] = 0x10;
-    assert_eq!(tx.cmd.as_ref(), expected_cmd.as_ref());
-    assert!(!status.is_bus_release_pending);
-    assert_eq!(status.bus_owner, BusOwner::ExternalMaster);
-    assert_eq!(status.password_attempt_count, 42);
-    assert!(status.is_password_guessed);
+/// True for the commands whose second byte is a sub-command code (the NVRAM commands).
+///
+/// For other commands the second byte is a parameter, e.g. the SPI data length of 0x42 or the
+/// EEPROM address of 0x50 and 0x51.
+#[cfg(any(feature = "log", feature = "tracing"))]
+fn has_sub_command(cmd_code: u8) -> bool {
+    matches!(cmd_code, 0x60 | 0x61)
 }
 
-#[test]
-fn test_cancel_spi_transfer() {
-    let mut tx = TestTx::new(&[0x11, 0x00, 0x00, 0x01, 79, 0x00]);
+/// Sends a sub-command and checks the response code, status and sub-command code.
+///
+/// Unknown status codes are reported as `Mcp2210Error::SubCommandStatus` with the sub-command
+/// code. Known error statuses keep their own variants, and with the `log` feature enabled they are
+/// logged together with the sub-command code. HID errors name the sub-command in their context.
+fn sub_command<T, F>(
+    mcp: &mut T,
+    cmd_code: u8,
+    sub_cmd_code: u8,
+    res: &mut Buffer,
+    f: F,
+) -> Result<(), Mcp2210Error>
+where
+    T: CommandResponse + ?Sized,
+    F: FnOnce(&mut Buffer),
+{
+    command(mcp, cmd_code, res, |cmd| {
+        cmd[1] = sub_cmd_code;
+        f(cmd);
+    })
+    .map_err(|err| match err {
+        Mcp2210Error::UnknownErrorCode {
+            cmd_code,
+            error_code,
+        } => Mcp2210Error::SubCommandStatus {
+            cmd_code,
+            sub_cmd_code,
+            status: error_code,
+        },
+        err @ Mcp2210Error::Hid { .. } => err,
+        err => {
+            #[cfg(feature = "log")]
+            log::debug!(
+                "command {:02x} (sub-command {:02x}) failed: {}",
+                cmd_code,
+                sub_cmd_code,
+                err
+            );
+            err
+        }
+    })?;
+    if res[2] != sub_cmd_code {
+        Err(Mcp2210Error::SubCommandCode {
+            expected: sub_cmd_code,
+            actual: res[2],
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Maximum length of the USB product and vendor names in UTF-16 code units.
+const MAX_USB_STRING_LEN: usize = 29;
+
+fn check_usb_string(name: &str) -> Result<usize, Mcp2210Error> {
+    if let Some(ch) = name.chars().find(|&ch| u32::from(ch) > 0xffff) {
+        return Err(Mcp2210Error::InvalidString(ch));
+    }
+    let size = name.encode_utf16().count();
+    if size > MAX_USB_STRING_LEN {
+        return Err(Mcp2210Error::StringSize {
+            limit: MAX_USB_STRING_LEN,
+            actual: size,
+        });
+    }
+    Ok(size)
+}
+
+fn decode_usb_string(res: &Buffer) -> Result<Vec<u16>, Mcp2210Error> {
+    let descriptor_len = res[4] as usize;
+    if !(2..=60).contains(&descriptor_len) || descriptor_len % 2 != 0 {
+        return Err(Mcp2210Error::InvalidResponse(format!(
+            "Invalid USB string descriptor length: {:02x}",
+            res[4]
+        )));
+    }
+    let str_bytes = descriptor_len - 2;
+    Ok(res[6..][..str_bytes]
+        .chunks(2)
+        .map(|chunk| as_u16(chunk[0], chunk[1]))
+        .collect())
+}
+
+fn transfer_to_end<T, F>(mcp: &mut T, mut data: &[u8], mut sink: F) -> Result<usize, Mcp2210Error>
+where
+    T: Commands + ?Sized,
+    F: FnMut(&[u8]) -> Result<(), Mcp2210Error>,
+{
+    if data.is_empty() {
+        return Ok(0);
+    }
+    let mut received = 0;
+    let mut res: Buffer = [0; 64];
+    {
+        let len = min(data.len(), MAX_SPI_PAYLOAD);
+        let res = mcp.spi_transfer(&data[..len], &mut res)?;
+        data = &data[len..];
+        if res.status != SpiTransferStatus::Started {
+            return Err(Mcp2210Error::TransferStatus(res.status));
+        }
+    }
+    loop {
+        let len = min(data.len(), MAX_SPI_PAYLOAD);
+        match mcp.spi_transfer(&data[..len], &mut res) {
+            Ok(res) => {
+                data = &data[len..];
+                sink(res.data)?;
+                received += res.data.len();
+                if res.status == SpiTransferStatus::Finished {
+                    break;
+                }
+            }
+            Err(Mcp2210Error::Busy) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(received)
+}
+
+/// Sets `bytes_per_tx` to `len` unless it already has that value.
+fn ensure_bytes_per_tx<T: Commands + ?Sized>(mcp: &mut T, len: usize) -> Result<(), Mcp2210Error> {
+    let settings = mcp.get_spi_transfer_settings()?;
+    if usize::from(settings.bytes_per_tx.get()) != len {
+        mcp.set_spi_transfer_settings(&settings.with_bytes_per_tx_for(len)?)?;
+    }
+    Ok(())
+}
+
+fn spi_transfer_word<T: Commands + ?Sized, const N: usize>(
+    mcp: &mut T,
+    data: [u8; N],
+) -> Result<[u8; N], Mcp2210Error> {
+    let mut buf = [0; N];
+    let len = mcp.spi_transfer_to_end_into(&data, &mut buf)?;
+    if len != N {
+        return Err(Mcp2210Error::UnexpectedLength {
+            expected: N,
+            actual: len,
+        });
+    }
+    Ok(buf)
+}
+
+/// Iterator returned by [`CommandsExt::spi_transfer_chunked`].
+pub struct SpiTransferChunks<'a, 'b, T: ?Sized> {
+    mcp: &'a mut T,
+    data: &'b [u8],
+    frame_index: u32,
+    done: bool,
+}
+
+impl<'a, 'b, T: Commands + ?Sized> Iterator for SpiTransferChunks<'a, 'b, T> {
+    type Item = Result<OwnedSpiTransferResponse, Mcp2210Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let len = min(self.data.len(), MAX_SPI_PAYLOAD);
+        let mut res: Buffer = [0; 64];
+        loop {
+            match self.mcp.spi_transfer(&self.data[..len], &mut res) {
+                Ok(res) => {
+                    self.data = &self.data[len..];
+                    self.done = res.status == SpiTransferStatus::Finished;
+                    let frame_index = self.frame_index;
+                    self.frame_index += 1;
+                    return Some(Ok(OwnedSpiTransferResponse {
+                        frame_index,
+                        ..res.into()
+                    }));
+                }
+                Err(Mcp2210Error::Busy) => (),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+fn write_nvram_chip_settings<T: Commands + ?Sized>(
+    mcp: &mut T,
+    settings: &ChipSettings,
+    password: Option<&Password>,
+) -> Result<(), Mcp2210Error> {
+    let mut res: Buffer = [0; 64];
+    sub_command(mcp, 0x60, NVRAM_CHIP_SETTINGS_SUBCODE, &mut res, |cmd| {
+        settings.write_to_buffer(cmd);
+        if let Some(password) = password {
+            cmd[19..27].copy_from_slice(password.as_bytes());
+        }
+    })
+}
+
+/// Interval between counter reads in `poll_interrupt_counter`.
+pub const POLL_INTERRUPT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum number of empty transfers sent by `drain_spi_bus`.
+const DRAIN_SPI_ATTEMPTS: usize = 64;
+
+/// Number of attempts for writing a single EEPROM byte in `write_eeprom_range_with_progress`.
+const EEPROM_WRITE_ATTEMPTS: u32 = 3;
+const EEPROM_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+fn check_eeprom_range(start: u8, len: usize) -> Result<(), Mcp2210Error> {
+    if usize::from(start) + len > 256 {
+        return Err(Mcp2210Error::EepromRange { start, len });
+    }
+    Ok(())
+}
+
+fn soft_failure<T>(result: Result<T, Mcp2210Error>) -> Result<Option<T>, Mcp2210Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err @ Mcp2210Error::Hid { .. }) => Err(err),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+struct TestTx {
+    cmd: Buffer,
+    cmds: Vec<Buffer>,
+    res: Vec<Buffer>,
+}
+
+#[cfg(test)]
+impl TestTx {
+    fn new(res: &[u8]) -> TestTx {
+        TestTx::with_responses(&[res])
+    }
+    /// Responds with the given responses in order, repeating the last one once they run out.
+    fn with_responses(responses: &[&[u8]]) -> TestTx {
+        let mut tx = TestTx {
+            cmd: [0; 64],
+            cmds: Vec::new(),
+            res: Vec::new(),
+        };
+        for res in responses {
+            assert!(res.len() <= 64);
+            let mut buf = [0; 64];
+            buf[..res.len()].copy_from_slice(res);
+            tx.res.push(buf);
+        }
+        tx
+    }
+}
+
+#[cfg(test)]
+impl CommandResponse for TestTx {
+    fn command_response(&mut self, cmd: &Buffer, res: &mut Buffer) -> HidResult<()> {
+        self.cmd.copy_from_slice(cmd);
+        let idx = min(self.cmds.len(), self.res.len() - 1);
+        self.cmds.push(*cmd);
+        res.copy_from_slice(&self.res[idx]);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_get_chip_status() {
+    let mut tx = TestTx::new(&[0x10, 0x00, 0x01, 0x02, 42, 0x01]);
+    let status = tx.get_chip_status().unwrap();
+    let mut expected_cmd = [0; 64];
+    expected_cmd[0] = 0x10;
+    assert_eq!(tx.cmd.as_ref(), expected_cmd.as_ref());
+    assert!(!status.is_bus_release_pending);
+    assert_eq!(status.bus_owner, BusOwner::ExternalMaster);
+    assert_eq!(status.password_attempt_count, 42);
+    assert!(status.is_password_guessed);
+}
+
+#[test]
+fn test_get_chip_status_retries_busy() {
+    let mut tx = TestTx::with_responses(&[&[0x10, 0xf8], &[0x10, 0x00, 0x00, 0x00, 0x00]]);
+    tx.get_chip_status().unwrap();
+    assert_eq!(tx.cmds.len(), 2);
+    let mut tx = TestTx::new(&[0x10, 0xf8]);
+    assert!(matches!(tx.get_chip_status(), Err(Mcp2210Error::Busy)));
+    assert_eq!(tx.cmds.len(), 2);
+}
+
+#[test]
+fn test_unknown_error_code() {
+    let mut tx = TestTx::new(&[0x10, 0x42]);
+    assert!(matches!(
+        tx.get_chip_status(),
+        Err(Mcp2210Error::UnknownErrorCode {
+            cmd_code: 0x10,
+            error_code: 0x42
+        })
+    ));
+}
+
+#[test]
+fn test_sub_command_unknown_status() {
+    let mut tx = TestTx::new(&[0x61, 0x42, 0x20]);
+    assert!(matches!(
+        tx.get_nvram_chip_settings(),
+        Err(Mcp2210Error::SubCommandStatus {
+            cmd_code: 0x61,
+            sub_cmd_code: 0x20,
+            status: 0x42
+        })
+    ));
+}
+
+#[test]
+fn test_sub_command_known_status() {
+    let mut tx = TestTx::new(&[0x60, 0xfb, 0x20]);
+    assert!(matches!(
+        tx.set_nvram_chip_settings_with_password(&ChipSettings::default(), None),
+        Err(Mcp2210Error::AccessDenied)
+    ));
+}
+
+#[test]
+fn test_hid_error_context() {
+    struct FailingTx;
+    impl CommandResponse for FailingTx {
+        fn command_response(&mut self, _: &Buffer, _: &mut Buffer) -> HidResult<()> {
+            Err(hidapi::HidError::HidApiError {
+                message: "HID API error".into(),
+            })
+        }
+    }
+    let err = FailingTx.get_nvram_chip_settings().unwrap_err();
+    assert!(matches!(
+        err,
+        Mcp2210Error::Hid {
+            context: "get_nvram_chip_settings",
+            ..
+        }
+    ));
+    assert!(err.to_string().ends_with("during get_nvram_chip_settings"));
+}
+
+#[test]
+fn test_cancel_spi_transfer() {
+    let mut tx = TestTx::new(&[0x11, 0x00, 0x00, 0x01, 79, 0x00]);
     let status = tx.cancel_spi_transfer().unwrap();
     let mut expected_cmd = [0; 64];
     expected_cmd[0] = 0x11;
@@ -337,3 +1570,1030 @@ fn test_cancel_spi_transfer() {
     assert_eq!(status.password_attempt_count, 79);
     assert!(!status.is_password_guessed);
 }
+
+#[test]
+fn test_drain_spi_bus() {
+    let mut tx = TestTx::new(&[0x11, 0x00, 0x01, 0x01, 0, 0x00]);
+    let status = tx.drain_spi_bus().unwrap();
+    assert_eq!(status.bus_owner, BusOwner::UsbBridge);
+    let mut expected_cmd = [0; 64];
+    expected_cmd[0] = 0x11;
+    assert_eq!(tx.cmd.as_ref(), expected_cmd.as_ref());
+}
+
+#[test]
+fn test_drain_spi_bus_busy() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x11, 0xf8],
+        &[0x42, 0xf8],
+        &[0x42, 0x00, 0x00, 0x30],
+        &[0x42, 0x00, 0x00, 0x10],
+        &[0x10, 0x00, 0x01, 0x00, 0, 0x00],
+    ]);
+    let status = tx.drain_spi_bus().unwrap();
+    assert_eq!(status.bus_owner, BusOwner::None);
+    assert_eq!(tx.cmds.len(), 5);
+    assert_eq!(tx.cmds[1][..2], [0x42, 0x00]);
+    assert_eq!(tx.cmds[4][0], 0x10);
+
+    let mut tx = TestTx::with_responses(&[&[0x11, 0xf8], &[0x42, 0xf8]]);
+    assert!(matches!(tx.drain_spi_bus(), Err(Mcp2210Error::Timeout)));
+    assert_eq!(tx.cmds.len(), 1 + DRAIN_SPI_ATTEMPTS);
+}
+
+#[test]
+fn test_self_test_passed() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x50, 0x00, 0x00, 0xff],
+        &[0x33, 0x00, 0x00, 0x00, 0x03, 0x00],
+        &[0x32, 0x00],
+        &[0x12, 0x00, 0x00, 0x00, 0x07, 0x00],
+        &[0x12, 0x00, 0x00, 0x00, 0x00, 0x00],
+    ]);
+    let result = tx.self_test().unwrap();
+    assert!(result.passed());
+    assert_eq!(tx.cmds.len(), 5);
+    assert_eq!(tx.cmds[2][..6], [0x32, 0x00, 0x00, 0x00, 0x03, 0x00]);
+    assert_eq!(tx.cmds[3][..2], [0x12, 0x00]);
+    assert_eq!(tx.cmds[4][..2], [0x12, 0xff]);
+}
+
+#[test]
+fn test_self_test_soft_failures() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x50, 0xfb],
+        &[0x33, 0xf8],
+        &[0x12, 0x00, 0x00, 0x00, 0x07, 0x00],
+        &[0x12, 0x00, 0x00, 0x00, 0x01, 0x00],
+    ]);
+    let result = tx.self_test().unwrap();
+    assert_eq!(
+        result,
+        SelfTestResult {
+            eeprom_readable: false,
+            gpio_configurable: false,
+            interrupt_counter_resettable: false,
+        }
+    );
+    assert!(!result.passed());
+    assert_eq!(tx.cmds.len(), 4);
+}
+
+#[test]
+fn test_get_and_reset_interrupt_counter() {
+    let mut tx = TestTx::new(&[0x12, 0x00, 0x00, 0x00, 0x34, 0x12]);
+    assert_eq!(tx.get_and_reset_interrupt_counter().unwrap(), 0x1234);
+    let mut expected_cmd = [0; 64];
+    expected_cmd[0] = 0x12;
+    assert_eq!(tx.cmd.as_ref(), expected_cmd.as_ref());
+}
+
+#[test]
+fn test_interrupt_event_counter() {
+    let mut tx = TestTx::new(&[0x12, 0x00, 0x00, 0x00, 0x02, 0x00]);
+    assert_eq!(tx.interrupt_event_counter(false).unwrap(), 2);
+    assert_eq!(tx.cmd[..2], [0x12, 0xff]);
+    assert_eq!(tx.interrupt_event_counter(true).unwrap(), 2);
+    assert_eq!(tx.cmd[..2], [0x12, 0x00]);
+}
+
+#[test]
+fn test_poll_interrupt_counter() {
+    let mut tx = TestTx::new(&[0x12, 0x00, 0x00, 0x00, 0x05, 0x00]);
+    assert_eq!(
+        tx.poll_interrupt_counter(5, Duration::from_millis(20))
+            .unwrap(),
+        5
+    );
+    assert_eq!(tx.cmd[1], 0xff);
+    assert_eq!(tx.cmds.len(), 1);
+    assert!(matches!(
+        tx.poll_interrupt_counter(6, Duration::from_millis(30)),
+        Err(Mcp2210Error::Timeout)
+    ));
+    // At most one read per 10 ms interval, plus the first and the last one
+    assert!(tx.cmds.len() <= 1 + 30 / 10 + 2);
+}
+
+#[test]
+fn test_poll_interrupt_counter_with_interval() {
+    let mut tx = TestTx::new(&[0x12, 0x00, 0x00, 0x00, 0x05, 0x00]);
+    let interval = Duration::from_millis(5);
+    assert!(matches!(
+        tx.poll_interrupt_counter_with_interval(6, interval, Duration::from_millis(20)),
+        Err(Mcp2210Error::Timeout)
+    ));
+    // At most one read per interval, plus the first and the last one
+    assert!(tx.cmds.len() <= 20 / 5 + 2);
+}
+
+#[test]
+fn test_accept_bus_release_without_pending_request() {
+    let mut tx = TestTx::new(&[0x10, 0x00, 0x01, 0x01, 0, 0x00]);
+    assert!(matches!(
+        tx.accept_bus_release(),
+        Err(Mcp2210Error::NoBusReleasePending)
+    ));
+    assert_eq!(tx.cmd[0], 0x10);
+}
+
+#[test]
+fn test_read_eeprom_all() {
+    let mut tx = TestTx::new(&[0x50, 0x00, 0x00, 0xab]);
+    assert_eq!(tx.read_eeprom_all().unwrap(), [0xab; 256]);
+    assert_eq!(tx.cmds.len(), 256);
+    assert_eq!(tx.cmd[..2], [0x50, 0xff]);
+}
+
+#[test]
+fn test_read_eeprom_with_crc_mismatch() {
+    let mut tx = TestTx::new(&[0x50, 0x00, 0x00, 0x42]);
+    assert!(matches!(
+        tx.read_eeprom_with_crc(0x00, 0),
+        Err(Mcp2210Error::EepromCrcMismatch {
+            expected: 0x00,
+            actual: 0x42
+        })
+    ));
+}
+
+#[test]
+fn test_write_eeprom_with_crc_out_of_range() {
+    let mut tx = TestTx::new(&[0x51, 0x00]);
+    assert!(matches!(
+        tx.write_eeprom_with_crc(0xfe, &[0x01, 0x02]),
+        Err(Mcp2210Error::EepromRange {
+            start: 0xfe,
+            len: 3
+        })
+    ));
+}
+
+#[test]
+fn test_nvram_and_runtime_chip_settings_layout() {
+    let settings = ChipSettings {
+        gp0_mode: PinMode::ChipSelect,
+        gp6_mode: PinMode::Dedicated,
+        default_gpio_value: GpioValue::PIN1 | GpioValue::PIN8,
+        default_gpio_direction: GpioDirection::PIN2_INPUT,
+        remote_wakeup: true,
+        interrupt_mode: InterruptMode::LowPulses,
+        bus_release: false,
+        nvram_access_control: NvramAccessControl::Password,
+        ..Default::default()
+    };
+    let mut runtime = TestTx::new(&[0x21, 0x00]);
+    runtime.set_chip_settings(&settings).unwrap();
+    let mut nvram = TestTx::new(&[0x60, 0x00, 0x20]);
+    nvram
+        .set_nvram_chip_settings_with_password(&settings, None)
+        .unwrap();
+    assert_eq!(runtime.cmd[4..19], nvram.cmd[4..19]);
+
+    // Both get responses have the settings at offsets 4-18
+    let mut res: Buffer = [0; 64];
+    res[4..19].copy_from_slice(&runtime.cmd[4..19]);
+    res[0] = 0x20;
+    assert_eq!(TestTx::new(&res).get_chip_settings().unwrap(), settings);
+    res[0] = 0x61;
+    res[2] = 0x20;
+    assert_eq!(
+        TestTx::new(&res).get_nvram_chip_settings().unwrap(),
+        settings
+    );
+}
+
+#[test]
+fn test_nvram_usb_parameters_round_trip() {
+    let params = UsbParameters::default()
+        .with_requested_current_ma(300)
+        .unwrap();
+    let mut tx = TestTx::new(&[0x60, 0x00, 0x30]);
+    tx.set_nvram_usb_parameters(&params).unwrap();
+    // The set command stores the parameters at offsets 4-9, but the get response has them at
+    // 12-15 (VID and PID), 29 (power options) and 30 (requested current)
+    let mut res: Buffer = [0; 64];
+    res[0] = 0x61;
+    res[2] = 0x30;
+    res[12..16].copy_from_slice(&tx.cmd[4..8]);
+    res[29] = tx.cmd[8];
+    res[30] = tx.cmd[9];
+    let mut tx = TestTx::new(&res);
+    assert_eq!(tx.get_nvram_usb_parameters().unwrap(), params);
+}
+
+#[test]
+fn test_nvram_usb_parameters_are_factory() {
+    let mut res: Buffer = [0; 64];
+    res[0] = 0x61;
+    res[2] = 0x30;
+    res[12..16].copy_from_slice(&[0xd8, 0x04, 0xde, 0x00]);
+    res[29] = 0x80;
+    res[30] = 50;
+    assert!(TestTx::new(&res)
+        .nvram_usb_parameters_are_factory()
+        .unwrap());
+    res[30] = 250;
+    assert!(!TestTx::new(&res)
+        .nvram_usb_parameters_are_factory()
+        .unwrap());
+}
+
+#[test]
+fn test_get_nvram_usb_product_name() {
+    let mut tx = TestTx::new(&[
+        0x61, 0x00, 0x40, 0x00, 0x08, 0x03, b'a', 0x00, 0x00, 0xd8, b'b', 0x00,
+    ]);
+    assert_eq!(tx.get_nvram_usb_product_name().unwrap(), "a\u{fffd}b");
+    assert!(matches!(
+        tx.get_nvram_usb_product_name_strict(),
+        Err(Mcp2210Error::InvalidResponse(_))
+    ));
+    assert_eq!(tx.cmd[1], 0x40);
+}
+
+#[test]
+fn test_get_usb_descriptor_strings() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x61, 0x00, 0x40, 0x00, 0x04, 0x03, b'p', 0x00],
+        &[0x61, 0x00, 0x50, 0x00, 0x04, 0x03, b'v', 0x00],
+    ]);
+    assert_eq!(
+        tx.get_usb_descriptor_strings().unwrap(),
+        ("p".to_owned(), "v".to_owned())
+    );
+    assert_eq!(tx.cmds[0][1], 0x40);
+    assert_eq!(tx.cmds[1][1], 0x50);
+}
+
+#[test]
+fn test_set_nvram_usb_product_name_supplementary_char() {
+    let mut tx = TestTx::new(&[0x60, 0x00, 0x40]);
+    assert!(matches!(
+        tx.set_nvram_usb_product_name("USB \u{1f600}"),
+        Err(Mcp2210Error::InvalidString('\u{1f600}'))
+    ));
+    assert!(tx.cmds.is_empty());
+    tx.set_nvram_usb_product_name("USB \u{fffd}").unwrap();
+    assert_eq!(tx.cmds.len(), 1);
+}
+
+#[test]
+fn test_set_nvram_usb_product_name_length_limit() {
+    let mut tx = TestTx::new(&[0x60, 0x00, 0x40]);
+    let name = "x".repeat(29);
+    tx.set_nvram_usb_product_name(&name).unwrap();
+    assert_eq!(tx.cmd[4], 60);
+    assert_eq!(tx.cmd[62..64], [b'x', 0x00]);
+    assert!(matches!(
+        tx.set_nvram_usb_product_name(&"x".repeat(30)),
+        Err(Mcp2210Error::StringSize {
+            limit: 29,
+            actual: 30
+        })
+    ));
+    assert_eq!(tx.cmds.len(), 1);
+}
+
+#[test]
+fn test_get_nvram_usb_vendor_name_invalid_length() {
+    let mut tx = TestTx::new(&[0x61, 0x00, 0x50, 0x00, 0x00, 0x03]);
+    assert!(matches!(
+        tx.get_nvram_usb_vendor_name(),
+        Err(Mcp2210Error::InvalidResponse(_))
+    ));
+    let mut tx = TestTx::new(&[0x61, 0x00, 0x50, 0x00, 0x02, 0x03]);
+    assert_eq!(tx.get_nvram_usb_vendor_name().unwrap(), "");
+}
+
+#[test]
+fn test_raw_command() {
+    let mut tx = TestTx::new(&[0x99, 0x00, 0xaa]);
+    let mut payload = [0; 63];
+    payload[0] = 0x12;
+    payload[62] = 0x34;
+    let res = tx.raw_command(0x99, &payload).unwrap();
+    assert_eq!(res[2], 0xaa);
+    let mut expected_cmd = [0; 64];
+    expected_cmd[0] = 0x99;
+    expected_cmd[1] = 0x12;
+    expected_cmd[63] = 0x34;
+    assert_eq!(tx.cmd.as_ref(), expected_cmd.as_ref());
+}
+
+#[test]
+fn test_do_command_buf_override() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    struct CountingTx(TestTx, Rc<RefCell<Vec<u8>>>);
+    impl CommandResponse for CountingTx {
+        fn command_response(&mut self, cmd: &Buffer, res: &mut Buffer) -> HidResult<()> {
+            self.0.command_response(cmd, res)
+        }
+        fn do_command_buf(&mut self, cmd: &Buffer, res: &mut Buffer) -> Result<(), Mcp2210Error> {
+            self.1.borrow_mut().push(cmd[0]);
+            self.command_response(cmd, res)
+                .map_err(hid_error("counting"))?;
+            check_response(cmd[0], res)
+        }
+    }
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut tx = CountingTx(
+        TestTx::with_responses(&[&[0x10, 0x00, 0x00, 0x00, 0x00], &[0x61, 0x00, 0x20]]),
+        seen.clone(),
+    );
+    tx.get_chip_status().unwrap();
+    tx.get_nvram_chip_settings().unwrap();
+    assert_eq!(*seen.borrow(), [0x10, 0x61]);
+    let mut boxed: Box<dyn CommandResponse> = Box::new(tx);
+    boxed.get_nvram_chip_settings().unwrap();
+    assert_eq!(*seen.borrow(), [0x10, 0x61, 0x61]);
+}
+
+#[test]
+fn test_boxed_command_response() {
+    let mut tx: Box<dyn CommandResponse> =
+        Box::new(TestTx::new(&[0x10, 0x00, 0x01, 0x00, 0, 0x00]));
+    let status = tx.get_chip_status().unwrap();
+    assert_eq!(status.bus_owner, BusOwner::None);
+}
+
+#[test]
+fn test_spi_transfer_checked() {
+    let settings = SpiTransferSettings::default();
+    let mut tx = TestTx::new(&[0x42, 0x00, 0x00, 0x20]);
+    let mut res: Buffer = [0; 64];
+    assert!(matches!(
+        tx.spi_transfer_checked(&[0x01, 0x02], &settings, &mut res),
+        Err(Mcp2210Error::SettingsMismatch { expected, .. }) if expected.bytes_per_tx.get() == 2
+    ));
+    assert!(tx.cmds.is_empty());
+    let res = tx
+        .spi_transfer_checked(&[0x01, 0x02, 0x03, 0x04], &settings, &mut res)
+        .unwrap();
+    assert_eq!(res.status, SpiTransferStatus::Started);
+    assert_eq!(tx.cmds.len(), 1);
+    assert_eq!(tx.cmd[..6], [0x42, 0x04, 0x00, 0x00, 0x01, 0x02]);
+    let mut res: Buffer = [0; 64];
+    assert!(matches!(
+        tx.spi_transfer_checked(&[0; 61], &settings, &mut res),
+        Err(Mcp2210Error::PayloadSize {
+            limit: 60,
+            actual: 61
+        })
+    ));
+    assert_eq!(tx.cmds.len(), 1);
+}
+
+#[test]
+fn test_boxed_commands() {
+    let mut tx: Box<dyn Commands> = Box::new(TestTx::with_responses(&[
+        &[0x10, 0x00, 0x00, 0x00, 0x00],
+        &[0x42, 0x00, 0x00, 0x10],
+    ]));
+    tx.get_chip_status().unwrap();
+    assert_eq!(tx.spi_transfer_chunked(&[]).count(), 1);
+}
+
+#[test]
+fn test_spi_transfer_busy() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x42, 0xf8, 0x00, 0x00],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+    ]);
+    let mut res: Buffer = [0; 64];
+    assert!(matches!(
+        tx.spi_transfer(&[0x01, 0x02], &mut res),
+        Err(Mcp2210Error::Busy)
+    ));
+    let res = tx.spi_transfer(&[0x01, 0x02], &mut res).unwrap();
+    assert_eq!(res.data, [0xaa, 0x55]);
+}
+
+#[test]
+fn test_spi_transfer_chunked() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+    ]);
+    let chunks = tx
+        .spi_transfer_chunked(&[0x01, 0x02])
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        chunks,
+        [
+            OwnedSpiTransferResponse {
+                data: vec![],
+                status: SpiTransferStatus::Started,
+                frame_index: 0,
+            },
+            OwnedSpiTransferResponse {
+                data: vec![0xaa, 0x55],
+                status: SpiTransferStatus::Finished,
+                frame_index: 1,
+            }
+        ]
+    );
+    assert_eq!(tx.cmds[0][..6], [0x42, 0x02, 0x00, 0x00, 0x01, 0x02]);
+    assert_eq!(tx.cmds[1][..2], [0x42, 0x00]);
+}
+
+#[test]
+fn test_spi_assert_loopback() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+    ]);
+    tx.spi_assert_loopback(&[0xaa, 0x55]).unwrap();
+    assert_eq!(tx.cmds.len(), 2);
+    assert_eq!(tx.cmds[0][..6], [0x42, 0x02, 0x00, 0x00, 0xaa, 0x55]);
+    assert_eq!(tx.cmds[1][..2], [0x42, 0x00]);
+}
+
+#[test]
+fn test_spi_assert_loopback_mismatch() {
+    let mut tx =
+        TestTx::with_responses(&[&[0x42, 0x00, 0x00, 0x20], &[0x42, 0x00, 0x01, 0x10, 0xab]]);
+    match tx.spi_assert_loopback(&[0xaa]) {
+        Err(Mcp2210Error::LoopbackMismatch { sent, received }) => {
+            assert_eq!(sent, [0xaa]);
+            assert_eq!(received, [0xab]);
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn test_set_gpio_direction_checked() {
+    let mut tx = TestTx::new(&[0x20, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00]);
+    assert!(matches!(
+        tx.set_gpio_direction_checked(GpioDirection::PIN0_INPUT | GpioDirection::PIN1_INPUT),
+        Err(Mcp2210Error::PinModeConflict { pin: 1 })
+    ));
+    assert_eq!(tx.cmds.len(), 1);
+}
+
+#[test]
+fn test_set_gpio_direction_and_value() {
+    // PIN0 becomes an output and PIN1 becomes an input
+    let mut tx = TestTx::with_responses(&[
+        &[0x33, 0x00, 0x00, 0x00, 0x01, 0x00],
+        &[0x32, 0x00],
+        &[0x30, 0x00],
+        &[0x32, 0x00],
+    ]);
+    tx.set_gpio_direction_and_value(GpioDirection::PIN1_INPUT, GpioValue::PIN0)
+        .unwrap();
+    assert_eq!(tx.cmds.len(), 4);
+    assert_eq!(tx.cmds[1][..6], [0x32, 0x00, 0x00, 0x00, 0x03, 0x00]);
+    assert_eq!(tx.cmds[2][..6], [0x30, 0x00, 0x00, 0x00, 0x01, 0x00]);
+    assert_eq!(tx.cmds[3][..6], [0x32, 0x00, 0x00, 0x00, 0x02, 0x00]);
+}
+
+#[test]
+fn test_apply_chip_settings_diff() {
+    let settings = ChipSettings::default();
+    let mut res: Buffer = [0; 64];
+    settings.write_to_buffer(&mut res);
+    res[0] = 0x20;
+    let mut tx = TestTx::new(&res);
+    tx.apply_chip_settings_diff(&settings).unwrap();
+    assert_eq!(tx.cmds.len(), 1);
+    assert_eq!(tx.cmd[0], 0x20);
+
+    let changed = ChipSettings {
+        gp0_mode: PinMode::ChipSelect,
+        ..settings
+    };
+    let mut tx = TestTx::with_responses(&[&res, &[0x21, 0x00]]);
+    tx.apply_chip_settings_diff(&changed).unwrap();
+    assert_eq!(tx.cmds.len(), 2);
+    let mut expected: Buffer = [0; 64];
+    changed.write_to_buffer(&mut expected);
+    expected[0] = 0x21;
+    assert_eq!(tx.cmds[1].as_ref(), expected.as_ref());
+}
+
+#[test]
+fn test_verify_spi_transfer_settings() {
+    let settings = SpiTransferSettings::default();
+    let mut res: Buffer = [0; 64];
+    settings.write_to_buffer(&mut res);
+    res[0] = 0x41;
+    let mut tx = TestTx::new(&res);
+    tx.verify_spi_transfer_settings(&settings).unwrap();
+    let expected = SpiTransferSettings::for_mode(SpiMode::Mode3);
+    assert!(matches!(
+        tx.verify_spi_transfer_settings(&expected),
+        Err(Mcp2210Error::SettingsMismatch { actual, .. }) if actual == settings
+    ));
+}
+
+#[test]
+fn test_apply_spi_settings_if_changed() {
+    let settings = SpiTransferSettings::default();
+    let mut res: Buffer = [0; 64];
+    settings.write_to_buffer(&mut res);
+    res[0] = 0x41;
+    let mut tx = TestTx::new(&res);
+    assert!(!tx.apply_spi_settings_if_changed(&settings).unwrap());
+    assert_eq!(tx.cmds.len(), 1);
+    assert_eq!(tx.cmd[0], 0x41);
+
+    let changed = SpiTransferSettings::for_mode(SpiMode::Mode3);
+    let mut tx = TestTx::with_responses(&[&res, &[0x40, 0x00]]);
+    assert!(tx.apply_spi_settings_if_changed(&changed).unwrap());
+    assert_eq!(tx.cmds.len(), 2);
+    let mut expected: Buffer = [0; 64];
+    changed.write_to_buffer(&mut expected);
+    expected[0] = 0x40;
+    assert_eq!(tx.cmds[1].as_ref(), expected.as_ref());
+}
+
+#[test]
+fn test_spi_transfer_to_end() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x00, 0x30],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+    ]);
+    let mut buf = vec![0x00];
+    assert_eq!(tx.spi_transfer_to_end(&[0x01, 0x02], &mut buf).unwrap(), 2);
+    assert_eq!(buf, [0x00, 0xaa, 0x55]);
+    assert_eq!(tx.cmds.len(), 3);
+}
+
+#[test]
+fn test_spi_transfer_to_end_empty() {
+    let mut tx = TestTx::new(&[0x42, 0x00, 0x00, 0x20]);
+    let mut buf = Vec::new();
+    assert_eq!(tx.spi_transfer_to_end(&[], &mut buf).unwrap(), 0);
+    assert!(buf.is_empty());
+    assert!(tx.cmds.is_empty());
+}
+
+#[test]
+fn test_spi_transfer_to_end_exact() {
+    let responses: [&[u8]; 2] = [
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+    ];
+    let mut buf = Vec::new();
+    let mut tx = TestTx::with_responses(&responses);
+    tx.spi_transfer_to_end_exact(&[0x01, 0x02], 2, &mut buf)
+        .unwrap();
+    assert_eq!(buf, [0xaa, 0x55]);
+    let mut tx = TestTx::with_responses(&responses);
+    assert!(matches!(
+        tx.spi_transfer_to_end_exact(&[0x01, 0x02, 0x03], 3, &mut buf),
+        Err(Mcp2210Error::UnexpectedLength {
+            expected: 3,
+            actual: 2
+        })
+    ));
+}
+
+#[test]
+fn test_spi_transfer_to_end_into() {
+    let responses: &[&[u8]] = &[
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+    ];
+    let mut tx = TestTx::with_responses(responses);
+    let mut buf = [0; 3];
+    assert_eq!(
+        tx.spi_transfer_to_end_into(&[0x01, 0x02], &mut buf)
+            .unwrap(),
+        2
+    );
+    assert_eq!(buf, [0xaa, 0x55, 0x00]);
+    let mut tx = TestTx::with_responses(&[
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+        &[0x11, 0x00, 0x01, 0x00, 0x00, 0x00],
+    ]);
+    let mut buf = [0; 1];
+    assert!(matches!(
+        tx.spi_transfer_to_end_into(&[0x01, 0x02], &mut buf),
+        Err(Mcp2210Error::PayloadSize {
+            limit: 1,
+            actual: 2
+        })
+    ));
+    assert_eq!(tx.cmds.len(), 3);
+    assert_eq!(tx.cmds[2][0], 0x11);
+    let mut tx = TestTx::with_responses(&[
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xaa, 0x55],
+        &[0x11, 0xf8],
+    ]);
+    assert!(matches!(
+        tx.spi_transfer_to_end_into(&[0x01, 0x02], &mut buf),
+        Err(Mcp2210Error::PayloadSize {
+            limit: 1,
+            actual: 2
+        })
+    ));
+}
+
+#[test]
+fn test_spi_write_then_read() {
+    let mut settings: Buffer = [0; 64];
+    SpiTransferSettings::default().write_to_buffer(&mut settings);
+    settings[0] = 0x41;
+    let mut tx = TestTx::with_responses(&[
+        &settings,
+        &[0x40, 0x00],
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xff, 0xff],
+        &[0x40, 0x00],
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x03, 0x10, 0x01, 0x02, 0x03],
+        &[0x40, 0x00],
+    ]);
+    assert_eq!(
+        tx.spi_write_then_read(&[0x9f, 0x00], 3).unwrap(),
+        [0x01, 0x02, 0x03]
+    );
+    assert_eq!(tx.cmds.len(), 8);
+    assert_eq!(tx.cmds[1][18..20], [0x02, 0x00]);
+    assert_eq!(tx.cmds[2][..6], [0x42, 0x02, 0x00, 0x00, 0x9f, 0x00]);
+    assert_eq!(tx.cmds[4][18..20], [0x03, 0x00]);
+    assert_eq!(tx.cmds[5][..2], [0x42, 0x03]);
+    assert_eq!(tx.cmds[7][18..20], settings[18..20]);
+}
+
+#[test]
+fn test_spi_write_then_read_empty_halves() {
+    let mut tx = TestTx::new(&[]);
+    assert_eq!(tx.spi_write_then_read(&[], 0).unwrap(), []);
+    assert!(tx.cmds.is_empty());
+
+    let mut settings: Buffer = [0; 64];
+    SpiTransferSettings::default().write_to_buffer(&mut settings);
+    settings[0] = 0x41;
+    let mut tx = TestTx::with_responses(&[
+        &settings,
+        &[0x40, 0x00],
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0xff, 0xff],
+        &[0x40, 0x00],
+    ]);
+    assert_eq!(tx.spi_write_then_read(&[0x06, 0x00], 0).unwrap(), []);
+    assert_eq!(tx.cmds.len(), 5);
+    assert_eq!(tx.cmds[1][18..20], [0x02, 0x00]);
+    assert_eq!(tx.cmds[2][..6], [0x42, 0x02, 0x00, 0x00, 0x06, 0x00]);
+    assert_eq!(tx.cmds[4][18..20], settings[18..20]);
+
+    let mut tx = TestTx::with_responses(&[
+        &settings,
+        &[0x40, 0x00],
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0x01, 0x02],
+        &[0x40, 0x00],
+    ]);
+    assert_eq!(tx.spi_write_then_read(&[], 2).unwrap(), [0x01, 0x02]);
+    assert_eq!(tx.cmds.len(), 5);
+    assert_eq!(tx.cmds[1][18..20], [0x02, 0x00]);
+    assert_eq!(tx.cmds[2][..2], [0x42, 0x02]);
+}
+
+#[test]
+fn test_spi_u16() {
+    let mut settings: Buffer = [0; 64];
+    SpiTransferSettings::default()
+        .with_bytes_per_tx_for(2)
+        .unwrap()
+        .write_to_buffer(&mut settings);
+    settings[0] = 0x41;
+    let responses: &[&[u8]] = &[
+        &settings,
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0x12, 0x34],
+    ];
+    let mut tx = TestTx::with_responses(responses);
+    assert_eq!(tx.spi_read_u16(Endian::Big).unwrap(), 0x1234);
+    assert_eq!(tx.cmds.len(), 3);
+    let mut tx = TestTx::with_responses(responses);
+    assert_eq!(tx.spi_read_u16(Endian::Little).unwrap(), 0x3412);
+    let mut tx = TestTx::with_responses(responses);
+    tx.spi_write_u16(0xabcd, Endian::Big).unwrap();
+    assert_eq!(tx.cmds[1][..6], [0x42, 0x02, 0x00, 0x00, 0xab, 0xcd]);
+    let mut tx = TestTx::with_responses(responses);
+    tx.spi_write_u16(0xabcd, Endian::Little).unwrap();
+    assert_eq!(tx.cmds[1][..6], [0x42, 0x02, 0x00, 0x00, 0xcd, 0xab]);
+}
+
+#[test]
+fn test_spi_u16_sets_bytes_per_tx() {
+    let mut settings: Buffer = [0; 64];
+    SpiTransferSettings::default().write_to_buffer(&mut settings);
+    settings[0] = 0x41;
+    let mut tx = TestTx::with_responses(&[
+        &settings,
+        &[0x40, 0x00],
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x02, 0x10, 0x12, 0x34],
+    ]);
+    assert_eq!(tx.spi_read_u16(Endian::Big).unwrap(), 0x1234);
+    assert_eq!(tx.cmds.len(), 4);
+    assert_eq!(tx.cmds[1][0], 0x40);
+    assert_eq!(tx.cmds[1][18..20], [0x02, 0x00]);
+}
+
+#[test]
+fn test_spi_u32() {
+    let mut settings: Buffer = [0; 64];
+    SpiTransferSettings::default().write_to_buffer(&mut settings);
+    settings[0] = 0x41;
+    let responses: &[&[u8]] = &[
+        &settings,
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x04, 0x10, 0x12, 0x34, 0x56, 0x78],
+    ];
+    let mut tx = TestTx::with_responses(responses);
+    assert_eq!(tx.spi_read_u32(Endian::Big).unwrap(), 0x12345678);
+    let mut tx = TestTx::with_responses(responses);
+    assert_eq!(tx.spi_read_u32(Endian::Little).unwrap(), 0x78563412);
+    let mut tx = TestTx::with_responses(responses);
+    tx.spi_write_u32(0x01020304, Endian::Big).unwrap();
+    assert_eq!(tx.cmds.len(), 3);
+    assert_eq!(
+        tx.cmds[1][..8],
+        [0x42, 0x04, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04]
+    );
+    let mut tx = TestTx::with_responses(responses);
+    tx.spi_write_u32(0x01020304, Endian::Little).unwrap();
+    assert_eq!(
+        tx.cmds[1][..8],
+        [0x42, 0x04, 0x00, 0x00, 0x04, 0x03, 0x02, 0x01]
+    );
+}
+
+#[test]
+fn test_spi_u32_sets_bytes_per_tx() {
+    let mut settings: Buffer = [0; 64];
+    SpiTransferSettings::default()
+        .with_bytes_per_tx_for(2)
+        .unwrap()
+        .write_to_buffer(&mut settings);
+    settings[0] = 0x41;
+    let mut tx = TestTx::with_responses(&[
+        &settings,
+        &[0x40, 0x00],
+        &[0x42, 0x00, 0x00, 0x20],
+        &[0x42, 0x00, 0x04, 0x10, 0x12, 0x34, 0x56, 0x78],
+    ]);
+    assert_eq!(tx.spi_read_u32(Endian::Big).unwrap(), 0x12345678);
+    assert_eq!(tx.cmds.len(), 4);
+    assert_eq!(tx.cmds[1][0], 0x40);
+    assert_eq!(tx.cmds[1][18..20], [0x04, 0x00]);
+}
+
+#[test]
+fn test_configure_gpio_for_interrupt() {
+    let mut tx = TestTx::with_responses(&[&[0x20, 0x00], &[0x21, 0x00]]);
+    tx.configure_gpio_for_interrupt(InterruptMode::RisingEdges)
+        .unwrap();
+    assert_eq!(tx.cmds[1][0], 0x21);
+    assert_eq!(tx.cmds[1][10], PinMode::Dedicated as u8);
+    assert_eq!(
+        tx.cmds[1][17] & 0b1110,
+        (InterruptMode::RisingEdges as u8) << 1
+    );
+}
+
+#[test]
+fn test_configure_spi_with_cs() {
+    let mut spi_settings: Buffer = [0; 64];
+    SpiTransferSettings::default().write_to_buffer(&mut spi_settings);
+    spi_settings[0] = 0x41;
+    let mut tx =
+        TestTx::with_responses(&[&[0x20, 0x00], &[0x21, 0x00], &spi_settings, &[0x40, 0x00]]);
+    tx.configure_spi_with_cs(1_000_000, SpiMode::Mode3, 2)
+        .unwrap();
+    assert_eq!(tx.cmds[1][4..13], [0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(tx.cmds[3][4..8], 1_000_000u32.to_le_bytes());
+    assert_eq!(tx.cmds[3][8..12], [0xff, 0x01, 0xfb, 0x01]);
+    assert_eq!(tx.cmds[3][20], 0x03);
+    let mut tx = TestTx::new(&[0x20, 0x00]);
+    assert!(matches!(
+        tx.configure_spi_with_cs(1_000_000, SpiMode::Mode0, 9),
+        Err(Mcp2210Error::InvalidPin(9))
+    ));
+    assert_eq!(tx.cmds.len(), 1);
+}
+
+#[test]
+fn test_set_nvram_chip_settings_permanent_lock() {
+    let settings = ChipSettings {
+        nvram_access_control: NvramAccessControl::PermanentlyLocked,
+        ..Default::default()
+    };
+    let mut tx = TestTx::new(&[0x60, 0x00, 0x20]);
+    assert!(matches!(
+        tx.set_nvram_chip_settings_with_password(&settings, None),
+        Err(Mcp2210Error::WouldPermLock)
+    ));
+    assert!(tx.cmds.is_empty());
+    tx.set_nvram_chip_settings_locked(&settings, None, CommitPermanentLock)
+        .unwrap();
+    assert_eq!(tx.cmd[18], 0x80);
+}
+
+#[test]
+fn test_set_nvram_chip_settings_password() {
+    let mut tx = TestTx::new(&[0x60, 0x00, 0x20]);
+    let password = Password::new(*b"secret12");
+    tx.set_nvram_chip_settings_with_password(&ChipSettings::default(), Some(&password))
+        .unwrap();
+    assert_eq!(tx.cmd[19..27], *b"secret12");
+    let mut tx = TestTx::new(&[0x60, 0x00, 0x20]);
+    #[allow(deprecated)]
+    tx.set_nvram_chip_settings(&ChipSettings::default(), Some(b"secret12"))
+        .unwrap();
+    assert_eq!(tx.cmd[19..27], *b"secret12");
+}
+
+#[test]
+fn test_write_nvram_all() {
+    let snapshot = NvramSnapshot::default();
+    let mut tx = TestTx::with_responses(&[
+        &[0x60, 0x00, 0x10],
+        &[0x60, 0x00, 0x20],
+        &[0x60, 0x00, 0x30],
+        &[0x60, 0xfb, 0x40],
+    ]);
+    let password = Password::new(*b"secret12");
+    let err = tx.write_nvram_all(&snapshot, Some(&password)).unwrap_err();
+    assert!(matches!(
+        err,
+        Mcp2210Error::PartialNvramWrite { completed: 3, ref error }
+            if matches!(**error, Mcp2210Error::AccessDenied)
+    ));
+    assert_eq!(tx.cmds.len(), 4);
+    assert_eq!(tx.cmds[1][19..27], *b"secret12");
+}
+
+#[test]
+fn test_factory_reset_chip_settings() {
+    let mut tx = TestTx::new(&[0x21, 0x00]);
+    tx.factory_reset_chip_settings().unwrap();
+    let mut expected_cmd = [0; 64];
+    expected_cmd[0] = 0x21;
+    expected_cmd[13] = 0xff;
+    expected_cmd[14] = 0x01;
+    expected_cmd[15] = 0xff;
+    expected_cmd[16] = 0x01;
+    assert_eq!(tx.cmd.as_ref(), expected_cmd.as_ref());
+}
+
+#[test]
+fn test_read_gpio_confirmed() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02],
+        &[0x31, 0x00, 0x00, 0x00, 0x08, 0x00],
+    ]);
+    assert!(tx.read_gpio_confirmed(3).unwrap());
+    let mut tx = TestTx::new(&[0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02]);
+    assert!(matches!(
+        tx.read_gpio_confirmed(5),
+        Err(Mcp2210Error::PinModeConflict { pin: 5 })
+    ));
+    let mut tx = TestTx::new(&[]);
+    assert!(matches!(
+        tx.read_gpio_confirmed(9),
+        Err(Mcp2210Error::InvalidPin(9))
+    ));
+    assert!(tx.cmds.is_empty());
+}
+
+#[test]
+fn test_set_gpio_value_mask() {
+    let mut tx = TestTx::with_responses(&[&[0x31, 0x00, 0x00, 0x00, 0x0f, 0x00], &[0x30, 0x00]]);
+    tx.set_gpio_value_mask(GpioValue::PIN0 | GpioValue::PIN4, GpioValue::PIN4)
+        .unwrap();
+    assert_eq!(tx.cmds[1][..6], [0x30, 0x00, 0x00, 0x00, 0x1e, 0x00]);
+}
+
+#[test]
+fn test_write_eeprom_range_with_progress() {
+    let mut tx = TestTx::new(&[0x51, 0x00]);
+    let mut calls = Vec::new();
+    tx.write_eeprom_range_with_progress(0x20, &[0xaa, 0xbb, 0xcc], &mut |written, total| {
+        calls.push((written, total))
+    })
+    .unwrap();
+    assert_eq!(calls, [(1, 3), (2, 3), (3, 3)]);
+    assert_eq!(tx.cmds[2][..3], [0x51, 0x22, 0xcc]);
+}
+
+#[test]
+fn test_write_eeprom_checked() {
+    let mut tx = TestTx::with_responses(&[&[0x50, 0x00, 0x10, 0x42], &[0x51, 0x00]]);
+    assert_eq!(tx.write_eeprom_checked(0x10, 0xaa).unwrap(), 0x42);
+    assert_eq!(tx.cmds.len(), 2);
+    assert_eq!(tx.cmds[0][..2], [0x50, 0x10]);
+    assert_eq!(tx.cmds[1][..3], [0x51, 0x10, 0xaa]);
+    let mut tx = TestTx::new(&[0x50, 0xf8]);
+    assert!(matches!(
+        tx.write_eeprom_checked(0x10, 0xaa),
+        Err(Mcp2210Error::Busy)
+    ));
+    assert_eq!(tx.cmds.len(), 1);
+}
+
+#[test]
+fn test_write_eeprom_slice_retry() {
+    let mut tx = TestTx::with_responses(&[&[0x51, 0xfa], &[0x51, 0x00]]);
+    tx.write_eeprom_slice(0x20, &[0xaa, 0xbb]).unwrap();
+    assert_eq!(tx.cmds.len(), 3);
+    assert_eq!(tx.cmds[1][..3], [0x51, 0x20, 0xaa]);
+    let mut tx = TestTx::new(&[0x51, 0xfa]);
+    assert!(matches!(
+        tx.write_eeprom_slice(0x20, &[0xaa]),
+        Err(Mcp2210Error::EepromWriteAt { addr: 0x20 })
+    ));
+    assert_eq!(tx.cmds.len(), 3);
+}
+
+#[test]
+fn test_eeprom_u16_u32() {
+    let mut tx = TestTx::new(&[0x51, 0x00]);
+    tx.write_eeprom_u32(0x40, 0x1234_5678).unwrap();
+    assert_eq!(tx.cmds[0][..3], [0x51, 0x40, 0x78]);
+    assert_eq!(tx.cmds[3][..3], [0x51, 0x43, 0x12]);
+    assert!(matches!(
+        tx.write_eeprom_u16(0xff, 0),
+        Err(Mcp2210Error::EepromRange {
+            start: 0xff,
+            len: 2
+        })
+    ));
+    let mut tx = TestTx::with_responses(&[&[0x50, 0x00, 0x40, 0x34], &[0x50, 0x00, 0x41, 0x12]]);
+    assert_eq!(tx.read_eeprom_u16(0x40).unwrap(), 0x1234);
+}
+
+#[test]
+fn test_eeprom_string() {
+    let mut tx = TestTx::new(&[0x51, 0x00]);
+    tx.write_eeprom_string(0x30, "SN1", 5).unwrap();
+    assert_eq!(tx.cmds.len(), 5);
+    assert_eq!(tx.cmds[2][..3], [0x51, 0x32, b'1']);
+    assert_eq!(tx.cmds[4][..3], [0x51, 0x34, 0x00]);
+    assert!(matches!(
+        tx.write_eeprom_string(0x30, "SN123", 4),
+        Err(Mcp2210Error::EepromData(_))
+    ));
+    let mut tx = TestTx::with_responses(&[
+        &[0x50, 0x00, 0x30, b'S'],
+        &[0x50, 0x00, 0x31, b'N'],
+        &[0x50, 0x00, 0x32, 0x00],
+    ]);
+    assert_eq!(tx.read_eeprom_string(0x30, 3).unwrap(), "SN");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_write_eeprom_structured() {
+    let mut tx = TestTx::new(&[0x51, 0x00]);
+    tx.write_eeprom_structured(0x10, &0x1234u16).unwrap();
+    assert_eq!(tx.cmds.len(), 3);
+    assert_eq!(tx.cmds[0][..3], [0x51, 0x10, 0x02]);
+    assert_eq!(tx.cmds[1][..3], [0x51, 0x11, 0x34]);
+    assert_eq!(tx.cmds[2][..3], [0x51, 0x12, 0x12]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_read_eeprom_structured() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x50, 0x00, 0x10, 0x02],
+        &[0x50, 0x00, 0x11, 0x34],
+        &[0x50, 0x00, 0x12, 0x12],
+    ]);
+    assert_eq!(tx.read_eeprom_structured::<u16>(0x10).unwrap(), 0x1234);
+    assert_eq!(tx.cmds.len(), 3);
+    assert_eq!(tx.cmds[2][..2], [0x50, 0x12]);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_read_eeprom_structured_range() {
+    let mut tx = TestTx::with_responses(&[
+        &[0x50, 0x00, 0xfd, 0x02],
+        &[0x50, 0x00, 0xfe, 0x34],
+        &[0x50, 0x00, 0xff, 0x12],
+    ]);
+    assert_eq!(tx.read_eeprom_structured::<u16>(0xfd).unwrap(), 0x1234);
+    let mut tx = TestTx::new(&[0x50, 0x00, 0xff, 0x01]);
+    assert!(matches!(
+        tx.read_eeprom_structured::<u8>(0xff),
+        Err(Mcp2210Error::EepromRange {
+            start: 0xff,
+            len: 2
+        })
+    ));
+    assert_eq!(tx.cmds.len(), 1);
+}
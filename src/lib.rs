@@ -2,31 +2,111 @@
 //
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+mod builder;
 mod cmds;
 mod types;
 mod utils;
 
+pub use crate::builder::*;
 pub use crate::cmds::*;
 pub use crate::types::*;
+pub use crate::utils::{as_bool, as_bool_lenient, eeprom_crc8};
 
 use hidapi::{DeviceInfo, HidApi, HidDevice, HidError, HidResult};
-use std::cmp::min;
 use std::error::Error;
+use std::ffi::{CStr, CString};
 use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
 
 pub const FACTORY_VID: u16 = 0x04d8;
 pub const FACTORY_PID: u16 = 0x00de;
 
 #[derive(Debug)]
 pub enum Mcp2210Error {
-    Hid(HidError),
-    CommandCode { expected: u8, actual: u8 },
-    SubCommandCode { expected: u8, actual: u8 },
+    /// HID error, with the name of the operation that failed as context.
+    Hid {
+        context: &'static str,
+        error: HidError,
+    },
+    NoDeviceFound {
+        vid: u16,
+        pid: u16,
+    },
+    CommandCode {
+        expected: u8,
+        actual: u8,
+    },
+    SubCommandCode {
+        expected: u8,
+        actual: u8,
+    },
+    /// Unknown status code from an NVRAM sub-command.
+    ///
+    /// Known error statuses such as `AccessDenied` or `Busy` keep their own variants. For those,
+    /// the sub-command is in the `log` output and the `tracing` span of the command instead.
+    SubCommandStatus {
+        cmd_code: u8,
+        sub_cmd_code: u8,
+        status: u8,
+    },
     InvalidResponse(String),
-    UnknownErrorCode(u8),
-    StringSize(usize),
-    PayloadSize(usize),
+    UnknownErrorCode {
+        cmd_code: u8,
+        error_code: u8,
+    },
+    StringSize {
+        limit: usize,
+        actual: usize,
+    },
+    InvalidString(char),
+    PayloadSize {
+        limit: usize,
+        actual: usize,
+    },
+    EmptyPayload,
+    RequestedCurrent(u16),
+    BitRate(u32),
     TransferStatus(SpiTransferStatus),
+    Timeout,
+    NoBusReleasePending,
+    EepromRange {
+        start: u8,
+        len: usize,
+    },
+    EepromWriteAt {
+        addr: u8,
+    },
+    SettingsMismatch {
+        expected: SpiTransferSettings,
+        actual: SpiTransferSettings,
+    },
+    EepromCrcMismatch {
+        expected: u8,
+        actual: u8,
+    },
+    EepromData(String),
+    UnexpectedLength {
+        expected: usize,
+        actual: usize,
+    },
+    LoopbackMismatch {
+        sent: Vec<u8>,
+        received: Vec<u8>,
+    },
+    InvalidPinMode {
+        pin: u8,
+        value: u8,
+    },
+    InvalidPin(u8),
+    WouldPermLock,
+    PartialNvramWrite {
+        completed: u8,
+        error: Box<Mcp2210Error>,
+    },
+    PinModeConflict {
+        pin: u8,
+    },
 
     // MCP2210 error codes
     EepromWrite,            // 0xFA
@@ -42,7 +122,10 @@ impl fmt::Display for Mcp2210Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use crate::Mcp2210Error::*;
         match self {
-            Hid(err) => fmt::Display::fmt(err, f),
+            Hid { context, error } => write!(f, "{} during {}", error, context),
+            NoDeviceFound { vid, pid } => {
+                write!(f, "No MCP2210 found (VID={:#06x} PID={:#06x})", vid, pid)
+            }
             CommandCode { expected, actual } => write!(
                 f,
                 "Invalid command code (expected {:2x}, got {:2x})",
@@ -53,19 +136,91 @@ impl fmt::Display for Mcp2210Error {
                 "Invalid sub-command code (expected {:2x}, got {:2x})",
                 expected, actual
             ),
+            SubCommandStatus {
+                cmd_code,
+                sub_cmd_code,
+                status,
+            } => write!(
+                f,
+                "Unknown error code {:02x} for command {:02x} (sub-command {:02x})",
+                status, cmd_code, sub_cmd_code
+            ),
             InvalidResponse(response) => write!(f, "Invalid response ({})", response),
-            UnknownErrorCode(code) => write!(f, "Unknown error code {:2x}", code),
-            StringSize(size) => write!(
+            UnknownErrorCode {
+                cmd_code,
+                error_code,
+            } => write!(
+                f,
+                "Unknown error code {:02x} for command {:02x}",
+                error_code, cmd_code
+            ),
+            StringSize { limit, actual } => write!(
                 f,
-                "String is too long (expected at most 29 UTF-16 encoded u16 values, got {})",
-                size
+                "String is too long (expected at most {} UTF-16 encoded u16 values, got {})",
+                limit, actual
             ),
-            PayloadSize(size) => write!(
+            InvalidString(ch) => write!(
                 f,
-                "Payload is too big (expected at most 60 bytes, got {})",
-                size
+                "String contains a character outside the Basic Multilingual Plane (U+{:04X})",
+                u32::from(*ch)
             ),
-            TransferStatus(status) => write!(f, "Unexpected SPI transfer status {:?}", status),
+            PayloadSize { limit, actual } => write!(
+                f,
+                "Payload is too big (expected at most {} bytes, got {})",
+                limit, actual
+            ),
+            EmptyPayload => write!(f, "Payload is empty"),
+            RequestedCurrent(ma) => write!(
+                f,
+                "Requested current is too high (expected at most 500 mA, got {} mA)",
+                ma
+            ),
+            BitRate(bit_rate) => write!(
+                f,
+                "Unsupported bit rate (expected {} to {} bps, got {})",
+                MIN_BIT_RATE, MAX_BIT_RATE, bit_rate
+            ),
+            TransferStatus(status) => write!(f, "Unexpected SPI transfer status {}", status),
+            Timeout => write!(f, "Operation timed out"),
+            NoBusReleasePending => write!(f, "No SPI bus release is pending"),
+            EepromRange { start, len } => write!(
+                f,
+                "EEPROM range out of bounds ({} bytes starting at {:02x})",
+                len, start
+            ),
+            EepromWriteAt { addr } => write!(f, "EEPROM write failed at address {:02x}", addr),
+            SettingsMismatch { expected, actual } => write!(
+                f,
+                "SPI transfer settings mismatch (expected {:?}, got {:?})",
+                expected, actual
+            ),
+            EepromCrcMismatch { expected, actual } => write!(
+                f,
+                "EEPROM CRC mismatch (expected {:02x}, got {:02x})",
+                expected, actual
+            ),
+            EepromData(err) => write!(f, "Invalid EEPROM data ({})", err),
+            UnexpectedLength { expected, actual } => write!(
+                f,
+                "Unexpected amount of SPI data (expected {} bytes, got {})",
+                expected, actual
+            ),
+            LoopbackMismatch { sent, received } => write!(
+                f,
+                "SPI loopback mismatch (sent {:02x?}, received {:02x?})",
+                sent, received
+            ),
+            InvalidPinMode { pin, value } => {
+                write!(f, "Invalid pin mode {:02x} for GP{}", value, pin)
+            }
+            WouldPermLock => write!(f, "Settings would permanently lock the NVRAM"),
+            PartialNvramWrite { completed, error } => write!(
+                f,
+                "NVRAM write failed after {} of 5 writes ({})",
+                completed, error
+            ),
+            InvalidPin(pin) => write!(f, "Invalid pin GP{}", pin),
+            PinModeConflict { pin } => write!(f, "GP{} is not in GPIO mode", pin),
             EepromWrite => write!(f, "EEPROM write failure"),
             AccessDenied => write!(f, "Access denied"),
             AccessRejected => write!(f, "Access rejected"),
@@ -81,20 +236,67 @@ impl Error for Mcp2210Error {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         use crate::Mcp2210Error::*;
         match self {
-            Hid(err) => Some(err),
-            _ => None,
+            Hid { error, .. } => Some(error),
+            PartialNvramWrite { error, .. } => Some(error.as_ref()),
+            // Listed explicitly so that new wrapping variants are not forgotten here
+            NoDeviceFound { .. }
+            | CommandCode { .. }
+            | SubCommandCode { .. }
+            | SubCommandStatus { .. }
+            | InvalidResponse(_)
+            | UnknownErrorCode { .. }
+            | StringSize { .. }
+            | InvalidString(_)
+            | PayloadSize { .. }
+            | EmptyPayload
+            | RequestedCurrent(_)
+            | BitRate(_)
+            | TransferStatus(_)
+            | Timeout
+            | NoBusReleasePending
+            | EepromRange { .. }
+            | EepromWriteAt { .. }
+            | SettingsMismatch { .. }
+            | EepromCrcMismatch { .. }
+            | EepromData(_)
+            | UnexpectedLength { .. }
+            | LoopbackMismatch { .. }
+            | InvalidPinMode { .. }
+            | InvalidPin(_)
+            | WouldPermLock
+            | PinModeConflict { .. }
+            | EepromWrite
+            | AccessDenied
+            | AccessRejected
+            | AccessDeniedRetry
+            | Unavailable
+            | Busy
+            | UnknownCommandCode(_) => None,
         }
     }
 }
 
-const BUFFER_SIZE: usize = 64;
+pub(crate) fn hid_error(context: &'static str) -> impl Fn(HidError) -> Mcp2210Error {
+    move |error| Mcp2210Error::Hid { context, error }
+}
+
+pub const BUFFER_SIZE: usize = 64;
 
 pub type Buffer = [u8; BUFFER_SIZE];
 
+pub const MIN_BIT_RATE: u32 = 1_500;
+
 pub const MAX_BIT_RATE: u32 = 12_000_000;
 
+/// Maximum number of SPI data bytes in a single transfer command.
+pub const MAX_SPI_PAYLOAD: usize = 60;
+
 pub struct Mcp2210 {
     device: HidDevice,
+    read_timeout: Option<Duration>,
+    path: Option<CString>,
+    vid_pid: Option<(u16, u16)>,
+    serial_number: Option<String>,
 }
 
 impl CommandResponse for Mcp2210 {
@@ -103,9 +305,30 @@ impl CommandResponse for Mcp2210 {
         data_to_write[0] = 0x00; // HID Report ID. For devices which only support a single report, this must be set to 0x0.
         data_to_write[1..].copy_from_slice(cmd);
         // At this point, length of data_to_write will be 1+BUFFER_SIZE == 65 and responses from the MCP2210 are always
-        // BUFFER_SIZE. Therefore, this should only take single reports and these asserts should be good assumptions.
-        assert_eq!(self.device.write(&data_to_write)?, data_to_write.len());
-        assert_eq!(self.device.read(res)?, BUFFER_SIZE);
+        // BUFFER_SIZE. Therefore, this should only take single reports, and anything else is reported as an error.
+        let written = self.device.write(&data_to_write)?;
+        if written != data_to_write.len() {
+            return Err(HidError::HidApiError {
+                message: format!("Short write ({} of {} bytes)", written, data_to_write.len()),
+            });
+        }
+        let read = match self.read_timeout {
+            Some(timeout) => {
+                let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+                self.device.read_timeout(res, timeout)?
+            }
+            None => self.device.read(res)?,
+        };
+        if read == 0 && self.read_timeout.is_some() {
+            return Err(HidError::HidApiError {
+                message: "Timed out waiting for a response".into(),
+            });
+        }
+        if read != BUFFER_SIZE {
+            return Err(HidError::HidApiError {
+                message: format!("Short read ({} of {} bytes)", read, BUFFER_SIZE),
+            });
+        }
         Ok(())
     }
 }
@@ -116,44 +339,153 @@ impl Mcp2210 {
     /// If the passed HidDevice is not actually a MCP2210 device, unexpected things are likely to happen when you
     /// use the Mcp2210 later.
     pub fn new(device: HidDevice) -> Mcp2210 {
-        Mcp2210 { device }
+        let info = device.get_device_info().ok();
+        Mcp2210 {
+            path: info
+                .as_ref()
+                .map(|info| info.path().to_owned())
+                .filter(|path| !path.as_bytes().is_empty()),
+            vid_pid: info
+                .as_ref()
+                .map(|info| (info.vendor_id(), info.product_id())),
+            serial_number: info
+                .as_ref()
+                .and_then(|info| info.serial_number())
+                .filter(|serial| !serial.is_empty())
+                .map(String::from),
+            device,
+            read_timeout: None,
+        }
     }
 
-    pub fn spi_transfer_to_end(
-        &mut self,
-        mut data: &[u8],
-        buf: &mut Vec<u8>,
-    ) -> Result<(), Mcp2210Error> {
-        let mut res: Buffer = [0; 64];
+    /// Reopens the device after it has been disconnected, keeping the read timeout.
+    ///
+    /// The device is reopened using the path it had when this `Mcp2210` was created. If that
+    /// fails, for example because the path changed after reconnecting, the device list of
+    /// `hidapi_context` is searched for a device with the same VID, PID and serial number, and the
+    /// stored path is updated. Refresh the device list with `HidApi::refresh_devices` first if the
+    /// device may have been enumerated again. Devices without a serial number can only be
+    /// reopened by path, so that a different device with the same VID and PID is never used.
+    pub fn reconnect(&mut self, hidapi_context: &HidApi) -> Result<(), Mcp2210Error> {
+        let err = match self
+            .path
+            .as_deref()
+            .map(|path| hidapi_context.open_path(path))
         {
-            let len = min(data.len(), 60);
-            let res = self.spi_transfer(&data[..len], &mut res)?;
-            data = &data[len..];
-            if res.status != SpiTransferStatus::Started {
-                return Err(Mcp2210Error::TransferStatus(res.status));
+            Some(Ok(device)) => {
+                self.device = device;
+                return Ok(());
             }
-        }
-        loop {
-            let len = min(data.len(), 60);
-            match self.spi_transfer(&data[..len], &mut res) {
-                Ok(res) => {
-                    data = &data[len..];
-                    buf.extend(res.data);
-                    if res.status == SpiTransferStatus::Finished {
-                        break;
-                    }
-                }
-                Err(Mcp2210Error::Busy) => (),
-                Err(err) => return Err(err),
+            Some(Err(err)) => err,
+            None => HidError::HidApiError {
+                message: "Unknown device path".into(),
+            },
+        };
+        if let (Some((vid, pid)), Some(serial)) = (self.vid_pid, &self.serial_number) {
+            let found = hidapi_context.device_list().find(|device_info| {
+                is_mcp2210_vid_pid(device_info, vid, pid)
+                    && device_info.serial_number() == Some(serial.as_str())
+            });
+            if let Some(device_info) = found {
+                self.device = device_info
+                    .open_device(hidapi_context)
+                    .map_err(hid_error("reconnect"))?;
+                self.path = Some(device_info.path().to_owned());
+                return Ok(());
             }
         }
-        Ok(())
+        Err(hid_error("reconnect")(err))
+    }
+
+    /// Sets how long to wait for a response from the device, or `None` to wait indefinitely.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl fmt::Debug for Mcp2210 {
+    /// Shows the device identity captured when the device was opened. This does no HID I/O.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mcp2210")
+            .field("path", &self.path)
+            .field("vid_pid", &self.vid_pid)
+            .field("serial_number", &self.serial_number)
+            .field("read_timeout", &self.read_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl From<HidDevice> for Mcp2210 {
+    fn from(device: HidDevice) -> Mcp2210 {
+        Mcp2210::new(device)
     }
 }
 
 /// True if the device has the MCP2210's factory Vendor ID (VID) and Product ID (VID).
 pub fn is_mcp2210(device_info: &DeviceInfo) -> bool {
-    device_info.vendor_id() == FACTORY_VID && device_info.product_id() == FACTORY_PID
+    is_mcp2210_vid_pid(device_info, FACTORY_VID, FACTORY_PID)
+}
+
+/// True if the device has the given Vendor ID (VID) and Product ID (PID).
+///
+/// Useful for devices whose NVRAM has been reprogrammed with a custom VID and PID.
+pub fn is_mcp2210_vid_pid(device_info: &DeviceInfo, vid: u16, pid: u16) -> bool {
+    device_info.vendor_id() == vid && device_info.product_id() == pid
+}
+
+/// Information about a connected MCP2210, as returned by [`list_devices`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mcp2210DeviceInfo {
+    /// Platform-specific device path
+    pub path: CString,
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+}
+
+impl Mcp2210DeviceInfo {
+    fn from_device_info(device_info: &DeviceInfo) -> Mcp2210DeviceInfo {
+        Mcp2210DeviceInfo {
+            path: device_info.path().to_owned(),
+            vid: device_info.vendor_id(),
+            pid: device_info.product_id(),
+            serial_number: device_info.serial_number().map(String::from),
+        }
+    }
+    /// Opens the device by its path.
+    pub fn open(&self, hidapi_context: &HidApi) -> Result<Mcp2210, Mcp2210Error> {
+        open_path(hidapi_context, &self.path)
+    }
+}
+
+/// Lists all HID devices with the MCP2210's factory Vendor ID (VID) and Product ID (PID).
+///
+/// This uses hidapi's device list, so it works on all platforms supported by hidapi. The paths are
+/// platform-specific, and are not necessarily filesystem paths (e.g. on Windows and macOS), but they
+/// can be passed to [`open_path`] as-is. The devices can also be opened with
+/// [`Mcp2210DeviceInfo::open`].
+pub fn list_devices(hidapi_context: &HidApi) -> Vec<Mcp2210DeviceInfo> {
+    hidapi_context
+        .device_list()
+        .filter(|device_info| is_mcp2210(device_info))
+        .map(Mcp2210DeviceInfo::from_device_info)
+        .collect()
+}
+
+/// Refreshes hidapi's device list and returns the paths of all HID devices with the MCP2210's
+/// factory Vendor ID (VID) and Product ID (PID).
+///
+/// This is a wrapper over [`list_devices`] that keeps the `PathBuf` return type. The paths are
+/// converted lossily, so a path that is not valid UTF-8 is not guaranteed to round-trip through
+/// [`open_path`]. Use [`list_devices`] to get the paths unchanged.
+pub fn scan_devices(hidapi_context: &mut HidApi) -> Result<Vec<PathBuf>, Mcp2210Error> {
+    hidapi_context
+        .refresh_devices()
+        .map_err(hid_error("scan_devices"))?;
+    Ok(list_devices(hidapi_context)
+        .into_iter()
+        .map(|device| PathBuf::from(device.path.to_string_lossy().into_owned()))
+        .collect())
 }
 
 /// Open the first HID device it finds with the MCP2210's factory Vendor ID (VID) and Product ID (PID).
@@ -162,8 +494,87 @@ pub fn is_mcp2210(device_info: &DeviceInfo) -> bool {
 /// found in the internal device list will be used. There are however no guarantees, which device this
 /// will be.
 pub fn open_first(hidapi_context: &HidApi) -> Result<Mcp2210, Mcp2210Error> {
+    open_vid_pid(hidapi_context, FACTORY_VID, FACTORY_PID)
+}
+
+/// Open the first HID device it finds with the given Vendor ID (VID) and Product ID (PID).
+///
+/// Returns `Mcp2210Error::NoDeviceFound` if the hidapi device list has no device with the VID and
+/// PID.
+pub fn open_vid_pid(hidapi_context: &HidApi, vid: u16, pid: u16) -> Result<Mcp2210, Mcp2210Error> {
+    let mcp = hidapi_context.open(vid, pid).map_err(|err| {
+        if hidapi_context
+            .device_list()
+            .any(|device_info| is_mcp2210_vid_pid(device_info, vid, pid))
+        {
+            hid_error("open_vid_pid")(err)
+        } else {
+            Mcp2210Error::NoDeviceFound { vid, pid }
+        }
+    })?;
+    Ok(Mcp2210::new(mcp))
+}
+
+/// Open the HID device with the given Vendor ID (VID), Product ID (PID) and serial number.
+pub fn open_serial(
+    hidapi_context: &HidApi,
+    vid: u16,
+    pid: u16,
+    serial: &str,
+) -> Result<Mcp2210, Mcp2210Error> {
+    let mcp = hidapi_context
+        .open_serial(vid, pid, serial)
+        .map_err(hid_error("open_serial"))?;
+    Ok(Mcp2210::new(mcp))
+}
+
+/// Open the HID device with the given platform-specific device path.
+pub fn open_path(hidapi_context: &HidApi, path: &CStr) -> Result<Mcp2210, Mcp2210Error> {
     let mcp = hidapi_context
-        .open(FACTORY_VID, FACTORY_PID)
-        .map_err(Mcp2210Error::Hid)?;
+        .open_path(path)
+        .map_err(hid_error("open_path"))?;
     Ok(Mcp2210::new(mcp))
 }
+
+#[test]
+fn test_error_source_chain() {
+    fn chain_len(err: &dyn Error) -> usize {
+        let mut len = 0;
+        let mut source = err.source();
+        while let Some(err) = source {
+            len += 1;
+            source = err.source();
+        }
+        len
+    }
+    let hid = || Mcp2210Error::Hid {
+        context: "get_chip_status",
+        error: HidError::HidApiError {
+            message: "HID API error".into(),
+        },
+    };
+    assert_eq!(chain_len(&hid()), 1);
+    let partial = |error| Mcp2210Error::PartialNvramWrite {
+        completed: 1,
+        error: Box::new(error),
+    };
+    assert_eq!(chain_len(&partial(Mcp2210Error::AccessDenied)), 1);
+    assert_eq!(chain_len(&partial(hid())), 2);
+    assert!(partial(hid())
+        .source()
+        .and_then(Error::source)
+        .unwrap()
+        .is::<HidError>());
+    for err in [
+        Mcp2210Error::NoDeviceFound {
+            vid: FACTORY_VID,
+            pid: FACTORY_PID,
+        },
+        Mcp2210Error::InvalidResponse(String::new()),
+        Mcp2210Error::EepromData(String::new()),
+        Mcp2210Error::Timeout,
+        Mcp2210Error::Busy,
+    ] {
+        assert_eq!(chain_len(&err), 0);
+    }
+}
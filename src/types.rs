@@ -3,12 +3,15 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use bitflags::bitflags;
+use std::cmp::Ordering;
 use std::fmt;
+use std::num::NonZeroU16;
 
 use crate::utils::{as_bool, as_u16, as_u32};
-use crate::{Buffer, MAX_BIT_RATE};
+use crate::{Buffer, Mcp2210Error, MAX_BIT_RATE, MAX_SPI_PAYLOAD, MIN_BIT_RATE};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ChipStatus {
     pub is_bus_release_pending: bool,
     pub bus_owner: BusOwner,
@@ -17,17 +20,36 @@ pub struct ChipStatus {
 }
 
 impl ChipStatus {
-    pub fn from_buffer(buf: &Buffer) -> Result<ChipStatus, String> {
+    pub fn from_buffer(buf: &Buffer) -> Result<ChipStatus, Mcp2210Error> {
         Ok(ChipStatus {
             is_bus_release_pending: !as_bool(buf[2])
-                .map_err(|v| format!("Invalid is_bus_release_pending value: {:02x}", v))?,
+                .map_err(|v| invalid_value("Invalid is_bus_release_pending value", v))?,
             bus_owner: BusOwner::from_u8(buf[3])
-                .map_err(|v| format!("Invalid bus_owner value: {:02x}", v))?,
+                .map_err(|v| invalid_value("Invalid bus_owner value", v))?,
             password_attempt_count: buf[4],
             is_password_guessed: as_bool(buf[5])
-                .map_err(|v| format!("Invalid is_password_guessed value: {:02x}", v))?,
+                .map_err(|v| invalid_value("Invalid is_password_guessed value", v))?,
         })
     }
+    /// True if the SPI bus is free for the USB bridge to use right now.
+    pub fn can_use_spi(&self) -> bool {
+        !self.is_bus_release_pending
+            && (self.bus_owner == BusOwner::UsbBridge || self.bus_owner == BusOwner::None)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SelfTestResult {
+    pub eeprom_readable: bool,
+    pub gpio_configurable: bool,
+    pub interrupt_counter_resettable: bool,
+}
+
+impl SelfTestResult {
+    pub fn passed(&self) -> bool {
+        self.eeprom_readable && self.gpio_configurable && self.interrupt_counter_resettable
+    }
 }
 
 bitflags!(
@@ -47,17 +69,49 @@ bitflags!(
     }
 );
 
+impl FromIterator<u8> for ChipSelect {
+    /// Builds a chip select mask from 0-based pin indices.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a pin index is greater than 8.
+    fn from_iter<I: IntoIterator<Item = u8>>(pins: I) -> ChipSelect {
+        pins.into_iter().fold(ChipSelect::empty(), |cs, pin| {
+            assert!(pin <= 8, "Invalid chip select pin {}", pin);
+            cs | ChipSelect::from_bits_truncate(1 << pin)
+        })
+    }
+}
+
 bitflags!(
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub struct GpioValue: u16 {
+        const PIN0 = 0b0_0000_0001;
+        const PIN1 = 0b0_0000_0010;
+        const PIN2 = 0b0_0000_0100;
+        const PIN3 = 0b0_0000_1000;
+        const PIN4 = 0b0_0001_0000;
+        const PIN5 = 0b0_0010_0000;
+        const PIN6 = 0b0_0100_0000;
+        const PIN7 = 0b0_1000_0000;
+        const PIN8 = 0b1_0000_0000;
+        #[deprecated(note = "use `GpioValue::PIN0` instead")]
         const GP0 = 0b0_0000_0001;
+        #[deprecated(note = "use `GpioValue::PIN1` instead")]
         const GP1 = 0b0_0000_0010;
+        #[deprecated(note = "use `GpioValue::PIN2` instead")]
         const GP2 = 0b0_0000_0100;
+        #[deprecated(note = "use `GpioValue::PIN3` instead")]
         const GP3 = 0b0_0000_1000;
+        #[deprecated(note = "use `GpioValue::PIN4` instead")]
         const GP4 = 0b0_0001_0000;
+        #[deprecated(note = "use `GpioValue::PIN5` instead")]
         const GP5 = 0b0_0010_0000;
+        #[deprecated(note = "use `GpioValue::PIN6` instead")]
         const GP6 = 0b0_0100_0000;
+        #[deprecated(note = "use `GpioValue::PIN7` instead")]
         const GP7 = 0b0_1000_0000;
+        #[deprecated(note = "use `GpioValue::PIN8` instead")]
         const GP8 = 0b1_0000_0000;
         const ALL_HIGH = 0b1_1111_1111;
         const ALL_LOW = 0b0_0000_0000;
@@ -73,27 +127,90 @@ impl Default for GpioValue {
 bitflags!(
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub struct GpioDirection: u16 {
+        const PIN0_INPUT = 0b0_0000_0001;
+        const PIN1_INPUT = 0b0_0000_0010;
+        const PIN2_INPUT = 0b0_0000_0100;
+        const PIN3_INPUT = 0b0_0000_1000;
+        const PIN4_INPUT = 0b0_0001_0000;
+        const PIN5_INPUT = 0b0_0010_0000;
+        const PIN6_INPUT = 0b0_0100_0000;
+        const PIN7_INPUT = 0b0_1000_0000;
+        const PIN8_INPUT = 0b1_0000_0000;
+        #[deprecated(note = "use `GpioDirection::PIN0_INPUT` instead")]
         const GP0DIR = 0b0_0000_0001;
+        #[deprecated(note = "use `GpioDirection::PIN1_INPUT` instead")]
         const GP1DIR = 0b0_0000_0010;
+        #[deprecated(note = "use `GpioDirection::PIN2_INPUT` instead")]
         const GP2DIR = 0b0_0000_0100;
+        #[deprecated(note = "use `GpioDirection::PIN3_INPUT` instead")]
         const GP3DIR = 0b0_0000_1000;
+        #[deprecated(note = "use `GpioDirection::PIN4_INPUT` instead")]
         const GP4DIR = 0b0_0001_0000;
+        #[deprecated(note = "use `GpioDirection::PIN5_INPUT` instead")]
         const GP5DIR = 0b0_0010_0000;
+        #[deprecated(note = "use `GpioDirection::PIN6_INPUT` instead")]
         const GP6DIR = 0b0_0100_0000;
+        #[deprecated(note = "use `GpioDirection::PIN7_INPUT` instead")]
         const GP7DIR = 0b0_1000_0000;
+        #[deprecated(note = "use `GpioDirection::PIN8_INPUT` instead")]
         const GP8DIR = 0b1_0000_0000;
         const ALL_INPUTS = 0b1_1111_1111;
         const ALL_OUTPUTS = 0b0_0000_0000;
     }
 );
 
+impl GpioDirection {
+    /// Builds a direction where the given 0-based pins are inputs and all other pins are outputs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a pin index is greater than 8.
+    pub fn inputs(pins: impl IntoIterator<Item = u8>) -> GpioDirection {
+        pins.into_iter()
+            .fold(GpioDirection::ALL_OUTPUTS, |direction, pin| {
+                assert!(pin <= 8, "Invalid GPIO pin {}", pin);
+                direction | GpioDirection::from_bits_truncate(1 << pin)
+            })
+    }
+    /// Builds a direction where the given 0-based pins are outputs and all other pins are inputs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a pin index is greater than 8.
+    pub fn outputs(pins: impl IntoIterator<Item = u8>) -> GpioDirection {
+        GpioDirection::ALL_INPUTS - GpioDirection::inputs(pins)
+    }
+}
+
 impl Default for GpioDirection {
     fn default() -> GpioDirection {
         GpioDirection::ALL_INPUTS
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for ChipSelect {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "ChipSelect({=u16:#011b})", self.bits());
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for GpioValue {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "GpioValue({=u16:#011b})", self.bits());
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for GpioDirection {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "GpioDirection({=u16:#011b})", self.bits());
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PinMode {
     Gpio = 0x00,
     ChipSelect = 0x01,
@@ -109,9 +226,24 @@ impl PinMode {
             _ => Err(v),
         }
     }
+    fn from_buffer(buf: &Buffer, pin: u8) -> Result<PinMode, Mcp2210Error> {
+        PinMode::from_u8(buf[4 + pin as usize])
+            .map_err(|value| Mcp2210Error::InvalidPinMode { pin, value })
+    }
+}
+
+impl fmt::Display for PinMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinMode::Gpio => write!(f, "GPIO"),
+            PinMode::ChipSelect => write!(f, "chip select"),
+            PinMode::Dedicated => write!(f, "dedicated function"),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum BusOwner {
     None,
     UsbBridge,
@@ -127,9 +259,26 @@ impl BusOwner {
             _ => Err(v),
         }
     }
+    pub fn is_external(&self) -> bool {
+        *self == BusOwner::ExternalMaster
+    }
+    pub fn is_usb_bridge(&self) -> bool {
+        *self == BusOwner::UsbBridge
+    }
+}
+
+impl fmt::Display for BusOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusOwner::None => write!(f, "none"),
+            BusOwner::UsbBridge => write!(f, "USB bridge"),
+            BusOwner::ExternalMaster => write!(f, "external master"),
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct UsbParameters {
     vid: u16,
     pid: u16,
@@ -151,14 +300,40 @@ impl Default for UsbParameters {
 }
 
 impl UsbParameters {
-    pub fn from_buffer(buf: &Buffer) -> Result<UsbParameters, String> {
+    pub fn from_buffer(buf: &Buffer) -> Result<UsbParameters, Mcp2210Error> {
         Ok(UsbParameters {
             vid: as_u16(buf[12], buf[13]),
             pid: as_u16(buf[14], buf[15]),
             power_option: UsbPowerOption::from_u8(buf[29] >> 6)
-                .map_err(|v| format!("Invalid power_option value: {:02x}", v))?,
+                .map_err(|v| invalid_value("Invalid power_option value", v))?,
             remote_wakeup_capable: buf[29] & 0b10_0000 != 0,
-            requested_current: buf[30],
+            requested_current: {
+                #[cfg(feature = "log")]
+                if buf[30] > 250 {
+                    log::warn!(
+                        "requested current {} mA exceeds the USB limit of 500 mA",
+                        u16::from(buf[30]) * 2
+                    );
+                }
+                buf[30]
+            },
+        })
+    }
+    /// Requested bus current in milliamps.
+    pub fn requested_current_ma(&self) -> u16 {
+        u16::from(self.requested_current) * 2
+    }
+    /// Returns a copy of the parameters with the requested bus current set to `ma` milliamps.
+    ///
+    /// The device stores the current in 2 mA units, so odd values are rounded down. Returns
+    /// `Mcp2210Error::RequestedCurrent` if `ma` is above the 500 mA USB limit.
+    pub fn with_requested_current_ma(&self, ma: u16) -> Result<UsbParameters, Mcp2210Error> {
+        if ma > 500 {
+            return Err(Mcp2210Error::RequestedCurrent(ma));
+        }
+        Ok(UsbParameters {
+            requested_current: (ma / 2) as u8,
+            ..*self
         })
     }
     pub fn write_to_buffer(self, buf: &mut Buffer) {
@@ -185,13 +360,14 @@ impl fmt::Debug for UsbParameters {
             .field("remote_wakeup_capable", &self.remote_wakeup_capable)
             .field(
                 "requested_current",
-                &format_args!("{} mA", self.requested_current * 2),
+                &format_args!("{} mA", self.requested_current_ma()),
             )
             .finish()
     }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum UsbPowerOption {
     SelfPowered = 0b01,
     HostPowered = 0b10,
@@ -205,9 +381,25 @@ impl UsbPowerOption {
             _ => Err(v),
         }
     }
+    pub fn is_self_powered(&self) -> bool {
+        *self == UsbPowerOption::SelfPowered
+    }
+    pub fn is_host_powered(&self) -> bool {
+        *self == UsbPowerOption::HostPowered
+    }
+}
+
+impl fmt::Display for UsbPowerOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsbPowerOption::SelfPowered => write!(f, "self-powered"),
+            UsbPowerOption::HostPowered => write!(f, "host-powered"),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ChipSettings {
     pub gp0_mode: PinMode,
     pub gp1_mode: PinMode,
@@ -226,35 +418,100 @@ pub struct ChipSettings {
     pub nvram_access_control: NvramAccessControl,
 }
 
+impl Default for ChipSettings {
+    fn default() -> ChipSettings {
+        ChipSettings {
+            gp0_mode: PinMode::Gpio,
+            gp1_mode: PinMode::Gpio,
+            gp2_mode: PinMode::Gpio,
+            gp3_mode: PinMode::Gpio,
+            gp4_mode: PinMode::Gpio,
+            gp5_mode: PinMode::Gpio,
+            gp6_mode: PinMode::Gpio,
+            gp7_mode: PinMode::Gpio,
+            gp8_mode: PinMode::Gpio,
+            default_gpio_value: GpioValue::default(),
+            default_gpio_direction: GpioDirection::default(),
+            remote_wakeup: false,
+            interrupt_mode: InterruptMode::default(),
+            bus_release: true,
+            nvram_access_control: NvramAccessControl::default(),
+        }
+    }
+}
+
 impl ChipSettings {
-    pub fn from_buffer(buf: &Buffer) -> Result<ChipSettings, String> {
+    /// Returns the mode of the given pin (0-8), or `None` if there is no such pin.
+    pub fn pin_mode(&self, pin: u8) -> Option<PinMode> {
+        match pin {
+            0 => Some(self.gp0_mode),
+            1 => Some(self.gp1_mode),
+            2 => Some(self.gp2_mode),
+            3 => Some(self.gp3_mode),
+            4 => Some(self.gp4_mode),
+            5 => Some(self.gp5_mode),
+            6 => Some(self.gp6_mode),
+            7 => Some(self.gp7_mode),
+            8 => Some(self.gp8_mode),
+            _ => None,
+        }
+    }
+    /// Checks whether the GP6 pin mode and the interrupt mode agree.
+    ///
+    /// The interrupt counter only counts when GP6 is in its dedicated function, so a dedicated GP6
+    /// with interrupts disabled, or interrupts enabled without a dedicated GP6, is inconsistent.
+    pub fn interrupt_config_is_consistent(&self) -> bool {
+        (self.gp6_mode == PinMode::Dedicated) != self.interrupt_mode.is_disabled()
+    }
+    /// Stable numeric representation of the settings.
+    ///
+    /// Different settings always have different keys, and the key is used for the `Ord`
+    /// implementation.
+    pub fn canonical_key(&self) -> u64 {
+        let pins = (0..9).fold(0u64, |key, pin| {
+            (key << 2) | self.pin_mode(pin).map_or(0, |mode| mode as u64)
+        });
+        (pins << 39)
+            | (u64::from(self.default_gpio_value.bits()) << 23)
+            | (u64::from(self.default_gpio_direction.bits()) << 7)
+            | (u64::from(self.remote_wakeup) << 6)
+            | ((self.interrupt_mode as u64) << 3)
+            | (u64::from(self.bus_release) << 2)
+            | ((self.nvram_access_control as u64) >> 6)
+    }
+    pub(crate) fn pin_mode_mut(&mut self, pin: u8) -> Option<&mut PinMode> {
+        match pin {
+            0 => Some(&mut self.gp0_mode),
+            1 => Some(&mut self.gp1_mode),
+            2 => Some(&mut self.gp2_mode),
+            3 => Some(&mut self.gp3_mode),
+            4 => Some(&mut self.gp4_mode),
+            5 => Some(&mut self.gp5_mode),
+            6 => Some(&mut self.gp6_mode),
+            7 => Some(&mut self.gp7_mode),
+            8 => Some(&mut self.gp8_mode),
+            _ => None,
+        }
+    }
+    pub fn from_buffer(buf: &Buffer) -> Result<ChipSettings, Mcp2210Error> {
         Ok(ChipSettings {
-            gp0_mode: PinMode::from_u8(buf[4])
-                .map_err(|v| format!("Invalid gp0_mode value: {:02x}", v))?,
-            gp1_mode: PinMode::from_u8(buf[5])
-                .map_err(|v| format!("Invalid gp1_mode value: {:02x}", v))?,
-            gp2_mode: PinMode::from_u8(buf[6])
-                .map_err(|v| format!("Invalid gp2_mode value: {:02x}", v))?,
-            gp3_mode: PinMode::from_u8(buf[7])
-                .map_err(|v| format!("Invalid gp3_mode value: {:02x}", v))?,
-            gp4_mode: PinMode::from_u8(buf[8])
-                .map_err(|v| format!("Invalid gp4_mode value: {:02x}", v))?,
-            gp5_mode: PinMode::from_u8(buf[9])
-                .map_err(|v| format!("Invalid gp5_mode value: {:02x}", v))?,
-            gp6_mode: PinMode::from_u8(buf[10])
-                .map_err(|v| format!("Invalid gp6_mode value: {:02x}", v))?,
-            gp7_mode: PinMode::from_u8(buf[11])
-                .map_err(|v| format!("Invalid gp7_mode value: {:02x}", v))?,
-            gp8_mode: PinMode::from_u8(buf[12])
-                .map_err(|v| format!("Invalid gp8_mode value: {:02x}", v))?,
+            gp0_mode: PinMode::from_buffer(buf, 0)?,
+            gp1_mode: PinMode::from_buffer(buf, 1)?,
+            gp2_mode: PinMode::from_buffer(buf, 2)?,
+            gp3_mode: PinMode::from_buffer(buf, 3)?,
+            gp4_mode: PinMode::from_buffer(buf, 4)?,
+            gp5_mode: PinMode::from_buffer(buf, 5)?,
+            gp6_mode: PinMode::from_buffer(buf, 6)?,
+            gp7_mode: PinMode::from_buffer(buf, 7)?,
+            gp8_mode: PinMode::from_buffer(buf, 8)?,
             default_gpio_value: GpioValue::from_bits_truncate(as_u16(buf[13], buf[14])),
             default_gpio_direction: GpioDirection::from_bits_truncate(as_u16(buf[15], buf[16])),
             remote_wakeup: buf[17] & 0b10000 != 0,
             interrupt_mode: InterruptMode::from_u8((buf[17] >> 1) & 0b111)
-                .map_err(|v| format!("Invalid interrupt_mode value: {:02x}", v))?,
+                .map_err(|v| invalid_value("Invalid interrupt_mode value", v))?,
             bus_release: buf[17] & 0b1 == 0,
             nvram_access_control: NvramAccessControl::from_u8(buf[18])
-                .map_err(|v| format!("Invalid nvram_access_control value: {:02x}", v))?,
+                .map_err(|v| invalid_value("Invalid nvram_access_control value", v))?,
         })
     }
     pub fn write_to_buffer(&self, buf: &mut Buffer) {
@@ -280,7 +537,20 @@ impl ChipSettings {
     }
 }
 
+impl PartialOrd for ChipSettings {
+    fn partial_cmp(&self, other: &ChipSettings) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ChipSettings {
+    fn cmp(&self, other: &ChipSettings) -> Ordering {
+        self.canonical_key().cmp(&other.canonical_key())
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum NvramAccessControl {
     #[default]
     None,
@@ -297,9 +567,32 @@ impl NvramAccessControl {
             _ => Err(v),
         }
     }
+    pub fn requires_password(&self) -> bool {
+        *self == NvramAccessControl::Password
+    }
+    pub fn is_locked(&self) -> bool {
+        *self == NvramAccessControl::PermanentlyLocked
+    }
+}
+
+impl fmt::Display for NvramAccessControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NvramAccessControl::None => write!(f, "none"),
+            NvramAccessControl::Password => write!(f, "password protected"),
+            NvramAccessControl::PermanentlyLocked => write!(f, "permanently locked"),
+        }
+    }
 }
 
+/// Confirmation required by `Commands::set_nvram_chip_settings_locked`, which can permanently lock
+/// the NVRAM.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommitPermanentLock;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum InterruptMode {
     #[default]
     None = 0b000,
@@ -320,9 +613,34 @@ impl InterruptMode {
             _ => Err(v),
         }
     }
+    pub fn is_edge_triggered(&self) -> bool {
+        matches!(
+            self,
+            InterruptMode::FallingEdges | InterruptMode::RisingEdges
+        )
+    }
+    pub fn is_pulse_counting(&self) -> bool {
+        matches!(self, InterruptMode::LowPulses | InterruptMode::HighPulses)
+    }
+    pub fn is_disabled(&self) -> bool {
+        *self == InterruptMode::None
+    }
+}
+
+impl fmt::Display for InterruptMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterruptMode::None => write!(f, "none"),
+            InterruptMode::FallingEdges => write!(f, "falling edge"),
+            InterruptMode::RisingEdges => write!(f, "rising edge"),
+            InterruptMode::LowPulses => write!(f, "low pulse counting"),
+            InterruptMode::HighPulses => write!(f, "high pulse counting"),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct SpiTransferSettings {
     pub bit_rate: u32,
     pub cs_idle: ChipSelect,
@@ -330,7 +648,7 @@ pub struct SpiTransferSettings {
     pub delay_cs_to_data: u16,
     pub delay_last_data_to_cs: u16,
     pub delay_between_data: u16,
-    pub bytes_per_tx: u16,
+    pub bytes_per_tx: NonZeroU16,
     pub spi_mode: SpiMode,
 }
 
@@ -343,14 +661,74 @@ impl Default for SpiTransferSettings {
             delay_cs_to_data: 0,
             delay_last_data_to_cs: 0,
             delay_between_data: 0,
-            bytes_per_tx: 4,
+            bytes_per_tx: NonZeroU16::new(4).unwrap(),
             spi_mode: SpiMode::Mode0,
         }
     }
 }
 
 impl SpiTransferSettings {
-    pub fn from_buffer(buf: &Buffer) -> Result<SpiTransferSettings, String> {
+    /// Default settings (maximum bit rate, no delays) using the given SPI mode.
+    pub fn for_mode(spi_mode: SpiMode) -> SpiTransferSettings {
+        SpiTransferSettings {
+            spi_mode,
+            ..Default::default()
+        }
+    }
+    /// Returns a copy of the settings with the given bit rate.
+    ///
+    /// Returns `Mcp2210Error::BitRate` if `bit_rate` is outside `MIN_BIT_RATE..=MAX_BIT_RATE`.
+    pub fn with_bit_rate(&self, bit_rate: u32) -> Result<SpiTransferSettings, Mcp2210Error> {
+        let settings = SpiTransferSettings { bit_rate, ..*self };
+        settings.validate()?;
+        Ok(settings)
+    }
+    /// Returns a copy of the settings with `bytes_per_tx` matching a transfer of `data_len` bytes.
+    ///
+    /// `bytes_per_tx` covers the whole SPI transaction, which `spi_transfer_to_end` may split into
+    /// several transfer commands, so it is not limited to `MAX_SPI_PAYLOAD`. Returns
+    /// `Mcp2210Error::EmptyPayload` if `data_len` is zero, or `Mcp2210Error::PayloadSize` if it
+    /// does not fit in `bytes_per_tx`.
+    pub fn with_bytes_per_tx_for(
+        &self,
+        data_len: usize,
+    ) -> Result<SpiTransferSettings, Mcp2210Error> {
+        let bytes_per_tx = u16::try_from(data_len).map_err(|_| Mcp2210Error::PayloadSize {
+            limit: usize::from(u16::MAX),
+            actual: data_len,
+        })?;
+        Ok(SpiTransferSettings {
+            bytes_per_tx: NonZeroU16::new(bytes_per_tx).ok_or(Mcp2210Error::EmptyPayload)?,
+            ..*self
+        })
+    }
+    /// Checks that the settings are within the limits supported by the device.
+    ///
+    /// Returns `Mcp2210Error::BitRate` if `bit_rate` is outside
+    /// `MIN_BIT_RATE..=MAX_BIT_RATE`.
+    pub fn validate(&self) -> Result<(), Mcp2210Error> {
+        if !(MIN_BIT_RATE..=MAX_BIT_RATE).contains(&self.bit_rate) {
+            return Err(Mcp2210Error::BitRate(self.bit_rate));
+        }
+        Ok(())
+    }
+    /// Closest bit rate to `requested` that the device can generate by dividing its 12 MHz clock
+    /// by an integer.
+    pub fn actual_bit_rate(requested: u32) -> u32 {
+        let divider = (MAX_BIT_RATE / requested.max(1)).max(1);
+        let faster = MAX_BIT_RATE / divider;
+        let slower = MAX_BIT_RATE / (divider + 1);
+        if faster.abs_diff(requested) <= slower.abs_diff(requested) {
+            faster
+        } else {
+            slower
+        }
+    }
+    /// Number of transfer commands needed to send `data_len` bytes of SPI data.
+    pub fn chunks_needed(data_len: usize) -> usize {
+        (data_len + MAX_SPI_PAYLOAD - 1) / MAX_SPI_PAYLOAD
+    }
+    pub fn from_buffer(buf: &Buffer) -> Result<SpiTransferSettings, Mcp2210Error> {
         Ok(SpiTransferSettings {
             bit_rate: as_u32(buf[4], buf[5], buf[6], buf[7]),
             cs_idle: ChipSelect::from_bits_truncate(as_u16(buf[8], buf[9])),
@@ -358,9 +736,11 @@ impl SpiTransferSettings {
             delay_cs_to_data: as_u16(buf[12], buf[13]),
             delay_last_data_to_cs: as_u16(buf[14], buf[15]),
             delay_between_data: as_u16(buf[16], buf[17]),
-            bytes_per_tx: as_u16(buf[18], buf[19]),
+            bytes_per_tx: NonZeroU16::new(as_u16(buf[18], buf[19])).ok_or_else(|| {
+                Mcp2210Error::InvalidResponse("Invalid bytes_per_tx value: 0".to_owned())
+            })?,
             spi_mode: SpiMode::from_u8(buf[20])
-                .map_err(|v| format!("Invalid spi_mode value: {:02x}", v))?,
+                .map_err(|v| invalid_value("Invalid spi_mode value", v))?,
         })
     }
     pub fn write_to_buffer(&self, buf: &mut Buffer) {
@@ -380,13 +760,53 @@ impl SpiTransferSettings {
         buf[15] = (self.delay_last_data_to_cs >> 8) as u8;
         buf[16] = self.delay_between_data as u8;
         buf[17] = (self.delay_between_data >> 8) as u8;
-        buf[18] = self.bytes_per_tx as u8;
-        buf[19] = (self.bytes_per_tx >> 8) as u8;
+        let bytes_per_tx = self.bytes_per_tx.get();
+        buf[18] = bytes_per_tx as u8;
+        buf[19] = (bytes_per_tx >> 8) as u8;
         buf[20] = self.spi_mode as u8;
     }
 }
 
+/// Password protecting the NVRAM when `NvramAccessControl::Password` is used.
+///
+/// The `Debug` output does not include the password.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Password([u8; 8]);
+
+impl Password {
+    pub fn new(bytes: [u8; 8]) -> Password {
+        Password(bytes)
+    }
+    pub fn as_bytes(&self) -> &[u8; 8] {
+        &self.0
+    }
+}
+
+impl From<[u8; 8]> for Password {
+    fn from(bytes: [u8; 8]) -> Password {
+        Password(bytes)
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Password(..)")
+    }
+}
+
+/// All settings stored in the MCP2210 NVRAM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NvramSnapshot {
+    pub spi_transfer_settings: SpiTransferSettings,
+    pub chip_settings: ChipSettings,
+    pub usb_parameters: UsbParameters,
+    pub usb_product_name: String,
+    pub usb_vendor_name: String,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpiMode {
     Mode0 = 0x00,
     Mode1 = 0x01,
@@ -406,13 +826,110 @@ impl SpiMode {
     }
 }
 
+impl From<(bool, bool)> for SpiMode {
+    /// Converts a `(cpol, cpha)` pair to the corresponding SPI mode.
+    fn from((cpol, cpha): (bool, bool)) -> SpiMode {
+        match (cpol, cpha) {
+            (false, false) => SpiMode::Mode0,
+            (false, true) => SpiMode::Mode1,
+            (true, false) => SpiMode::Mode2,
+            (true, true) => SpiMode::Mode3,
+        }
+    }
+}
+
+impl From<SpiMode> for (bool, bool) {
+    /// Converts an SPI mode to its `(cpol, cpha)` pair.
+    fn from(mode: SpiMode) -> (bool, bool) {
+        match mode {
+            SpiMode::Mode0 => (false, false),
+            SpiMode::Mode1 => (false, true),
+            SpiMode::Mode2 => (true, false),
+            SpiMode::Mode3 => (true, true),
+        }
+    }
+}
+
+impl Default for NvramSnapshot {
+    fn default() -> NvramSnapshot {
+        NvramSnapshot {
+            spi_transfer_settings: SpiTransferSettings::default(),
+            chip_settings: ChipSettings::default(),
+            usb_parameters: UsbParameters::default(),
+            usb_product_name: "MCP2210 USB to SPI Master".to_owned(),
+            usb_vendor_name: "Microchip Technology Inc.".to_owned(),
+        }
+    }
+}
+
+/// Byte order of multi-byte SPI words.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+/// Starting points for SPI transfer settings of common SPI devices.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SpiPreset {
+    /// SD card in SPI mode, at the 400 kHz identification clock rate
+    SdCard,
+    /// Winbond W25Q series SPI flash
+    W25QFlash,
+    /// TI CC2500 2.4 GHz transceiver
+    Cc2500,
+    /// NXP MFRC522 RFID reader
+    Mfrc522,
+    /// Generic mode 0 device at 1 MHz
+    Generic1Mhz,
+}
+
+impl From<SpiPreset> for SpiTransferSettings {
+    fn from(preset: SpiPreset) -> SpiTransferSettings {
+        let bit_rate = match preset {
+            SpiPreset::SdCard => 400_000,
+            SpiPreset::W25QFlash => MAX_BIT_RATE,
+            SpiPreset::Cc2500 => 6_000_000,
+            SpiPreset::Mfrc522 => 4_000_000,
+            SpiPreset::Generic1Mhz => 1_000_000,
+        };
+        SpiTransferSettings {
+            bit_rate,
+            ..SpiTransferSettings::for_mode(SpiMode::Mode0)
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct SpiTransferResponse<'a> {
     pub data: &'a [u8],
     pub status: SpiTransferStatus,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OwnedSpiTransferResponse {
+    pub data: Vec<u8>,
+    pub status: SpiTransferStatus,
+    /// Index of this response within a chunked transfer, starting from zero.
+    pub frame_index: u32,
+}
+
+impl<'a> From<SpiTransferResponse<'a>> for OwnedSpiTransferResponse {
+    fn from(res: SpiTransferResponse<'a>) -> OwnedSpiTransferResponse {
+        OwnedSpiTransferResponse {
+            data: res.data.to_vec(),
+            status: res.status,
+            frame_index: 0,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpiTransferStatus {
     Started,
     Pending,
@@ -429,3 +946,376 @@ impl SpiTransferStatus {
         }
     }
 }
+
+impl fmt::Display for SpiTransferStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpiTransferStatus::Started => write!(f, "started"),
+            SpiTransferStatus::Pending => write!(f, "pending"),
+            SpiTransferStatus::Finished => write!(f, "finished"),
+        }
+    }
+}
+
+fn invalid_value(message: &str, v: u8) -> Mcp2210Error {
+    Mcp2210Error::InvalidResponse(format!("{}: {:02x}", message, v))
+}
+
+#[test]
+fn test_chip_settings_invalid_pin_mode() {
+    let mut buf: Buffer = [0; 64];
+    buf[7] = 0x03;
+    assert!(matches!(
+        ChipSettings::from_buffer(&buf),
+        Err(Mcp2210Error::InvalidPinMode {
+            pin: 3,
+            value: 0x03
+        })
+    ));
+}
+
+#[test]
+fn test_chunks_needed() {
+    assert_eq!(SpiTransferSettings::chunks_needed(0), 0);
+    assert_eq!(SpiTransferSettings::chunks_needed(1), 1);
+    assert_eq!(SpiTransferSettings::chunks_needed(60), 1);
+    assert_eq!(SpiTransferSettings::chunks_needed(61), 2);
+}
+
+#[test]
+fn test_with_bytes_per_tx_for() {
+    let settings = SpiTransferSettings::default();
+    assert_eq!(
+        settings
+            .with_bytes_per_tx_for(100)
+            .unwrap()
+            .bytes_per_tx
+            .get(),
+        100
+    );
+    assert!(matches!(
+        settings.with_bytes_per_tx_for(0),
+        Err(Mcp2210Error::EmptyPayload)
+    ));
+    assert!(matches!(
+        settings.with_bytes_per_tx_for(0x1_0000),
+        Err(Mcp2210Error::PayloadSize {
+            limit: 0xffff,
+            actual: 0x1_0000
+        })
+    ));
+}
+
+#[test]
+fn test_display() {
+    assert_eq!(InterruptMode::FallingEdges.to_string(), "falling edge");
+    assert_eq!(BusOwner::ExternalMaster.to_string(), "external master");
+    assert_eq!(NvramAccessControl::None.to_string(), "none");
+    assert_eq!(UsbPowerOption::SelfPowered.to_string(), "self-powered");
+    assert_eq!(PinMode::ChipSelect.to_string(), "chip select");
+    assert_eq!(SpiTransferStatus::Pending.to_string(), "pending");
+}
+
+#[test]
+fn test_chip_select_from_iter() {
+    assert_eq!(
+        ChipSelect::from_iter([0, 3, 8]),
+        ChipSelect::CS0 | ChipSelect::CS3 | ChipSelect::CS8
+    );
+    assert_eq!(
+        [1u8, 2].into_iter().collect::<ChipSelect>(),
+        ChipSelect::CS1 | ChipSelect::CS2
+    );
+}
+
+#[test]
+fn test_gpio_direction_inputs_outputs() {
+    assert_eq!(
+        GpioDirection::inputs([0, 8]),
+        GpioDirection::PIN0_INPUT | GpioDirection::PIN8_INPUT
+    );
+    assert_eq!(
+        GpioDirection::outputs([3]),
+        GpioDirection::ALL_INPUTS - GpioDirection::PIN3_INPUT
+    );
+}
+
+#[test]
+fn test_usb_parameters_requested_current() {
+    let params = UsbParameters::default();
+    assert_eq!(params.requested_current_ma(), 100);
+    let params = params.with_requested_current_ma(500).unwrap();
+    assert_eq!(params.requested_current_ma(), 500);
+    assert!(matches!(
+        params.with_requested_current_ma(502),
+        Err(Mcp2210Error::RequestedCurrent(502))
+    ));
+    let mut buf: Buffer = [0; 64];
+    buf[29] = 0x80;
+    buf[30] = 0xff;
+    let params = UsbParameters::from_buffer(&buf).unwrap();
+    assert_eq!(params.requested_current_ma(), 510);
+    assert!(format!("{:?}", params).contains("510 mA"));
+}
+
+#[test]
+fn test_spi_transfer_settings_validate() {
+    let mut settings = SpiTransferSettings::default();
+    assert!(settings.validate().is_ok());
+    settings.bit_rate = MIN_BIT_RATE;
+    assert!(settings.validate().is_ok());
+    settings.bit_rate = MIN_BIT_RATE - 1;
+    assert!(matches!(
+        settings.validate(),
+        Err(Mcp2210Error::BitRate(1_499))
+    ));
+    settings.bit_rate = MAX_BIT_RATE + 1;
+    assert!(settings.validate().is_err());
+    let settings = SpiTransferSettings::for_mode(SpiMode::Mode1);
+    assert_eq!(
+        settings.with_bit_rate(MIN_BIT_RATE).unwrap().bit_rate,
+        MIN_BIT_RATE
+    );
+    assert!(matches!(
+        settings.with_bit_rate(1_000),
+        Err(Mcp2210Error::BitRate(1_000))
+    ));
+}
+
+#[test]
+fn test_actual_bit_rate() {
+    assert_eq!(
+        SpiTransferSettings::actual_bit_rate(MAX_BIT_RATE),
+        MAX_BIT_RATE
+    );
+    assert_eq!(
+        SpiTransferSettings::actual_bit_rate(20_000_000),
+        MAX_BIT_RATE
+    );
+    assert_eq!(SpiTransferSettings::actual_bit_rate(1_000_000), 1_000_000);
+    assert_eq!(SpiTransferSettings::actual_bit_rate(5_000_000), 6_000_000);
+    assert_eq!(SpiTransferSettings::actual_bit_rate(3_500_000), 4_000_000);
+    assert_eq!(SpiTransferSettings::actual_bit_rate(3_300_000), 3_000_000);
+}
+
+#[test]
+fn test_chip_settings_interrupt_config_is_consistent() {
+    let settings = ChipSettings::default();
+    assert!(settings.interrupt_config_is_consistent());
+    let dedicated = ChipSettings {
+        gp6_mode: PinMode::Dedicated,
+        ..settings
+    };
+    assert!(!dedicated.interrupt_config_is_consistent());
+    assert!(ChipSettings {
+        interrupt_mode: InterruptMode::RisingEdges,
+        ..dedicated
+    }
+    .interrupt_config_is_consistent());
+    assert!(!ChipSettings {
+        interrupt_mode: InterruptMode::RisingEdges,
+        ..settings
+    }
+    .interrupt_config_is_consistent());
+}
+
+#[test]
+fn test_chip_settings_canonical_key() {
+    let settings = ChipSettings::default();
+    let changed = [
+        ChipSettings {
+            gp0_mode: PinMode::Dedicated,
+            ..settings
+        },
+        ChipSettings {
+            gp8_mode: PinMode::ChipSelect,
+            ..settings
+        },
+        ChipSettings {
+            default_gpio_value: GpioValue::empty(),
+            ..settings
+        },
+        ChipSettings {
+            default_gpio_direction: GpioDirection::outputs(0..9),
+            ..settings
+        },
+        ChipSettings {
+            remote_wakeup: true,
+            ..settings
+        },
+        ChipSettings {
+            interrupt_mode: InterruptMode::HighPulses,
+            ..settings
+        },
+        ChipSettings {
+            bus_release: false,
+            ..settings
+        },
+        ChipSettings {
+            nvram_access_control: NvramAccessControl::PermanentlyLocked,
+            ..settings
+        },
+    ];
+    let keys = changed
+        .iter()
+        .map(ChipSettings::canonical_key)
+        .collect::<std::collections::BTreeSet<_>>();
+    assert_eq!(keys.len(), changed.len());
+    assert!(!keys.contains(&settings.canonical_key()));
+    assert!(settings < changed[0]);
+}
+
+#[test]
+fn test_spi_transfer_settings_round_trip() {
+    let modes = [
+        SpiMode::Mode0,
+        SpiMode::Mode1,
+        SpiMode::Mode2,
+        SpiMode::Mode3,
+    ];
+    let cases = modes.iter().flat_map(|&spi_mode| {
+        [
+            SpiTransferSettings {
+                bit_rate: MIN_BIT_RATE,
+                cs_idle: ChipSelect::ALL_LOW,
+                cs_active: ChipSelect::ALL_HIGH,
+                delay_cs_to_data: 0,
+                delay_last_data_to_cs: 0,
+                delay_between_data: 0,
+                bytes_per_tx: NonZeroU16::new(1).unwrap(),
+                spi_mode,
+            },
+            SpiTransferSettings {
+                bit_rate: MAX_BIT_RATE,
+                cs_idle: ChipSelect::ALL_HIGH,
+                cs_active: ChipSelect::CS0 | ChipSelect::CS8,
+                delay_cs_to_data: u16::MAX,
+                delay_last_data_to_cs: u16::MAX,
+                delay_between_data: u16::MAX,
+                bytes_per_tx: NonZeroU16::new(u16::MAX).unwrap(),
+                spi_mode,
+            },
+        ]
+    });
+    for settings in cases {
+        let mut buf: Buffer = [0; 64];
+        settings.write_to_buffer(&mut buf);
+        assert_eq!(SpiTransferSettings::from_buffer(&buf).unwrap(), settings);
+    }
+    let mut buf: Buffer = [0; 64];
+    SpiTransferSettings::default().write_to_buffer(&mut buf);
+    buf[18] = 0;
+    buf[19] = 0;
+    assert!(matches!(
+        SpiTransferSettings::from_buffer(&buf),
+        Err(Mcp2210Error::InvalidResponse(_))
+    ));
+}
+
+#[test]
+fn test_spi_presets() {
+    let presets = [
+        (SpiPreset::SdCard, 400_000),
+        (SpiPreset::W25QFlash, MAX_BIT_RATE),
+        (SpiPreset::Cc2500, 6_000_000),
+        (SpiPreset::Mfrc522, 4_000_000),
+        (SpiPreset::Generic1Mhz, 1_000_000),
+    ];
+    for (preset, bit_rate) in presets {
+        let settings = SpiTransferSettings::from(preset);
+        assert_eq!(settings.spi_mode, SpiMode::Mode0, "{:?}", preset);
+        assert_eq!(settings.bit_rate, bit_rate, "{:?}", preset);
+        assert!(settings.validate().is_ok(), "{:?}", preset);
+    }
+}
+
+#[test]
+fn test_chip_settings_round_trip() {
+    let pin_modes = [PinMode::Gpio, PinMode::ChipSelect, PinMode::Dedicated];
+    let interrupt_modes = [
+        InterruptMode::None,
+        InterruptMode::FallingEdges,
+        InterruptMode::RisingEdges,
+        InterruptMode::LowPulses,
+        InterruptMode::HighPulses,
+    ];
+    let access_controls = [
+        NvramAccessControl::None,
+        NvramAccessControl::Password,
+        NvramAccessControl::PermanentlyLocked,
+    ];
+    let round_trip = |settings: ChipSettings| {
+        let mut buf: Buffer = [0; 64];
+        settings.write_to_buffer(&mut buf);
+        assert_eq!(ChipSettings::from_buffer(&buf).unwrap(), settings);
+    };
+    // Every combination of the three pin modes on the nine pins
+    for combination in 0..pin_modes.len().pow(9) {
+        let mut settings = ChipSettings::default();
+        let mut digits = combination;
+        for pin in 0..9 {
+            *settings.pin_mode_mut(pin).unwrap() = pin_modes[digits % pin_modes.len()];
+            digits /= pin_modes.len();
+        }
+        round_trip(settings);
+    }
+    for &interrupt_mode in &interrupt_modes {
+        for &nvram_access_control in &access_controls {
+            for flag in [false, true] {
+                round_trip(ChipSettings {
+                    default_gpio_value: if flag {
+                        GpioValue::all()
+                    } else {
+                        GpioValue::empty()
+                    },
+                    default_gpio_direction: if flag {
+                        GpioDirection::empty()
+                    } else {
+                        GpioDirection::all()
+                    },
+                    remote_wakeup: flag,
+                    interrupt_mode,
+                    bus_release: !flag,
+                    nvram_access_control,
+                    ..Default::default()
+                });
+                round_trip(ChipSettings {
+                    remote_wakeup: flag,
+                    bus_release: flag,
+                    interrupt_mode,
+                    nvram_access_control,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+}
+
+#[test]
+fn test_gpio_flags_xor_assign() {
+    let mut value = GpioValue::PIN0 | GpioValue::PIN3;
+    value ^= GpioValue::PIN3;
+    assert_eq!(value, GpioValue::PIN0);
+    value ^= GpioValue::PIN3;
+    assert_eq!(value, GpioValue::PIN0 | GpioValue::PIN3);
+    let mut direction = GpioDirection::PIN1_INPUT;
+    direction ^= GpioDirection::PIN1_INPUT | GpioDirection::PIN2_INPUT;
+    assert_eq!(direction, GpioDirection::PIN2_INPUT);
+}
+
+#[test]
+fn test_spi_mode_cpol_cpha() {
+    assert_eq!(SpiMode::from((false, false)), SpiMode::Mode0);
+    assert_eq!(SpiMode::from((false, true)), SpiMode::Mode1);
+    assert_eq!(SpiMode::from((true, false)), SpiMode::Mode2);
+    assert_eq!(SpiMode::from((true, true)), SpiMode::Mode3);
+    for mode in [
+        SpiMode::Mode0,
+        SpiMode::Mode1,
+        SpiMode::Mode2,
+        SpiMode::Mode3,
+    ] {
+        let pair: (bool, bool) = mode.into();
+        assert_eq!(SpiMode::from(pair), mode);
+    }
+}
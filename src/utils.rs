@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+/// Parses a strict boolean byte (0x00 or 0x01), returning the raw value for anything else.
 #[inline]
 pub fn as_bool(v: u8) -> Result<bool, u8> {
     match v {
@@ -11,6 +12,12 @@ pub fn as_bool(v: u8) -> Result<bool, u8> {
     }
 }
 
+/// Parses a boolean byte where any nonzero value is true.
+#[inline]
+pub fn as_bool_lenient(v: u8) -> bool {
+    v != 0x00
+}
+
 #[inline]
 pub fn as_u16(a: u8, b: u8) -> u16 {
     (u16::from(b) << 8) | u16::from(a)
@@ -31,6 +38,31 @@ pub fn encode_utf16_to_buffer(s: &str, buf: &mut [u8]) {
     }
 }
 
+/// Computes the CRC-8/MAXIM (1-Wire) checksum of the given data.
+pub fn eeprom_crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x01 != 0 {
+                (crc >> 1) ^ 0x8c
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[test]
+fn test_as_bool() {
+    assert_eq!(as_bool(0x00), Ok(false));
+    assert_eq!(as_bool(0x01), Ok(true));
+    assert_eq!(as_bool(0x02), Err(0x02));
+    assert!(!as_bool_lenient(0x00));
+    assert!(as_bool_lenient(0x02));
+}
+
 #[test]
 fn test_as_u16() {
     assert_eq!(as_u16(0xaa, 0x55), 0x55aa);
@@ -50,3 +82,9 @@ fn test_encode_utf16_to_buffer() {
         [0xf4, 0xbc, 0x7c, 0xb7, 0xc9, 0xc0, 0x20, 0x00, 0xe0, 0xac, 0x91, 0xc5, 0x74, 0xc7]
     );
 }
+
+#[test]
+fn test_eeprom_crc8() {
+    assert_eq!(eeprom_crc8(&[]), 0x00);
+    assert_eq!(eeprom_crc8(b"123456789"), 0xa1);
+}